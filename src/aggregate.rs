@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use geoconv::{CoordinateSystem, Degrees, Lle, Meters, Wgs84};
+
+use crate::ekf::{Real, Sensor};
+
+/// How long a module report is used for after it was received.
+pub const RETAIN_PERIOD: Duration = Duration::from_millis(250);
+/// Smallest ratio of the spread of the modules across their main axis to the spread along it
+/// before [`check_layout`] reports them as nearly collinear.
+pub const MIN_LAYOUT_RATIO: Real = 0.1;
+
+#[derive(Clone, Copy)]
+pub struct Module {
+    // pub mac: String,
+    // pub ip: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    pub drone: bool,
+    pub dist: f64,
+    /// When the report was received, used as its timestamp.
+    pub updated: Instant,
+    /// Id of the drone the distance belongs to, if the module reported one.
+    pub track: Option<u32>,
+}
+
+/// Checks that a module position is a usable latitude and longitude in degrees.
+pub fn check_position(lat: f64, lon: f64) -> Result<(), String> {
+    if !lat.is_finite() || lat.is_subnormal() || !(-90.0..=90.0).contains(&lat) {
+        return Err(format!("invalid latitude {lat}"));
+    }
+    if !lon.is_finite() || lon.is_subnormal() || !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("invalid longitude {lon}"));
+    }
+    Ok(())
+}
+
+/// Mean latitude and longitude of `points` at zero elevation, or `None` if there are none.
+pub fn centroid(points: impl Iterator<Item = (f64, f64)>) -> Option<Lle<Wgs84>> {
+    let (n, lat, lon) =
+        points.fold((0, 0.0, 0.0), |(n, lat, lon), (la, lo)| (n + 1, lat + la, lon + lo));
+    (n > 0).then(|| {
+        Lle::<Wgs84>::new(
+            Degrees::new(lat / n as f64),
+            Degrees::new(lon / n as f64),
+            Meters::new(0.0),
+        )
+    })
+}
+
+/// Standard deviations of the horizontal sensor positions along their principal axes, in
+/// meters, the smaller first, or `None` for fewer than 3 sensors.
+pub fn layout_spread(sensors: &[Sensor]) -> Option<(Real, Real)> {
+    if sensors.len() < 3 {
+        return None;
+    }
+    let n = sensors.len() as Real;
+    let points: Vec<[Real; 3]> = sensors.iter().map(Sensor::position).collect();
+    let mean_x = points.iter().map(|p| p[0]).sum::<Real>() / n;
+    let mean_y = points.iter().map(|p| p[1]).sum::<Real>() / n;
+    let (mut xx, mut xy, mut yy) = (0.0, 0.0, 0.0);
+    for p in &points {
+        let (dx, dy) = (p[0] - mean_x, p[1] - mean_y);
+        xx += dx * dx / n;
+        xy += dx * dy / n;
+        yy += dy * dy / n;
+    }
+    // eigenvalues of the 2x2 covariance of the positions
+    let mid = (xx + yy) / 2.0;
+    let half_gap = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    Some(((mid - half_gap).max(0.0).sqrt(), (mid + half_gap).sqrt()))
+}
+
+/// Warns if the sensors are nearly collinear, which leaves every fix poorly constrained across
+/// their line whatever the filter settings.
+pub fn check_layout(sensors: &[Sensor]) {
+    if let Some((minor, major)) = layout_spread(sensors)
+        && minor < MIN_LAYOUT_RATIO * major
+    {
+        log::warn!(
+            "The {} modules are nearly collinear, spread {minor:.1} m across their main axis \
+             against {major:.1} m along it (ratio {:.3}), expect poor cross-track accuracy",
+            sensors.len(),
+            minor / major.max(Real::MIN_POSITIVE)
+        );
+    }
+}
+
+/// Filter input aggregated from the retained module reports.
+pub enum Frame<K> {
+    /// No retained module reports a drone.
+    NoDetection,
+    /// Fewer than 3 modules retained, or than 2 with [`Aggregator::two_sensor`].
+    NotEnough,
+    /// Sensors to update the filter with, along with the keys of the modules they came from.
+    Ready { keys: Vec<K>, sensors: Vec<Sensor> },
+}
+
+/// Turns module reports into filter input the same way for `processor::run` and `simulate`.
+#[derive(Default)]
+pub struct Aggregator {
+    /// ENU reference, the centroid of the retained modules once there are enough for a frame,
+    /// unless set beforehand.
+    pub ref_lle: Option<Lle<Wgs84>>,
+    /// Build frames from two modules too, for [`crate::ekf::EkfConfig::two_sensor_noise_factor`].
+    pub two_sensor: bool,
+}
+
+impl Aggregator {
+    /// Drops modules not updated recently and picks the ENU reference if there is none yet.
+    pub fn retain<K: Ord>(&mut self, modules: &mut HashMap<K, Module>, now: Instant) {
+        // retain recently updated modules
+        modules.retain(|_, m| {
+            now.saturating_duration_since(m.updated) < RETAIN_PERIOD
+                && m.lon.is_finite()
+                && m.lat.is_finite()
+        });
+        if self.ref_lle.is_none() && modules.len() >= self.min_modules() {
+            self.ref_lle = centroid(sorted(modules).into_iter().map(|(_, m)| (m.lat, m.lon)));
+        }
+    }
+
+    /// Builds the filter input from the retained modules, ordered by key so that identical
+    /// reports give bit-identical estimates.
+    pub fn frame<K: Clone + Ord>(&self, modules: &HashMap<K, Module>) -> Frame<K> {
+        let detection = modules.iter().any(|(_, m)| m.drone);
+        if !detection {
+            return Frame::NoDetection;
+        }
+
+        // proceed with calculating drone position if enough modules retained
+        if modules.len() < self.min_modules() {
+            return Frame::NotEnough;
+        }
+
+        let ref_lle = self.ref_lle.as_ref().unwrap();
+        let (keys, sensors) = sorted(modules)
+            .into_iter()
+            .map(|(key, m)| {
+                let lle =
+                    Lle::<Wgs84>::new(Degrees::new(m.lat), Degrees::new(m.lon), Meters::new(m.alt));
+                let sensor = Sensor {
+                    enu: CoordinateSystem::lle_to_enu(ref_lle, &lle),
+                    dist: m.dist as Real,
+                    stddev: None,
+                    max_dist: None,
+                    bearing: None,
+                };
+                (key.clone(), sensor)
+            })
+            .unzip();
+        Frame::Ready { keys, sensors }
+    }
+
+    /// Fewest modules a frame is built from.
+    fn min_modules(&self) -> usize {
+        if self.two_sensor { 2 } else { 3 }
+    }
+}
+
+/// Entries of `map` in key order, as `HashMap` iteration order differs between runs.
+fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> Vec<(&K, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(lat: f64, lon: f64, dist: f64) -> Module {
+        Module {
+            lat,
+            lon,
+            alt: 0.0,
+            drone: true,
+            dist,
+            updated: Instant::now(),
+            track: None,
+        }
+    }
+
+    #[test]
+    fn first_frame_from_two_modules() {
+        let mut modules = HashMap::from([
+            (1, module(52.5767, 16.7681, 90.0)),
+            (2, module(52.5787, 16.77, 80.0)),
+        ]);
+        let mut aggregator = Aggregator {
+            ref_lle: None,
+            two_sensor: true,
+        };
+        aggregator.retain(&mut modules, Instant::now());
+        assert!(aggregator.ref_lle.is_some());
+        match aggregator.frame(&modules) {
+            Frame::Ready { keys, sensors } => {
+                assert_eq!(keys, [1, 2]);
+                assert_eq!(sensors.len(), 2);
+            }
+            _ => panic!("expected a frame from two modules"),
+        }
+
+        // without the two-sensor mode two modules are not enough, and pick no reference
+        let mut aggregator = Aggregator::default();
+        aggregator.retain(&mut modules, Instant::now());
+        assert!(aggregator.ref_lle.is_none());
+        assert!(matches!(aggregator.frame(&modules), Frame::NotEnough));
+    }
+
+    #[test]
+    fn accepts_positions_up_to_the_poles_and_the_antimeridian() {
+        for (lat, lon) in [(90.0, 180.0), (-90.0, -180.0), (90.0, -180.0), (0.0, 0.0)] {
+            assert!(check_position(lat, lon).is_ok(), "{lat}, {lon}");
+        }
+    }
+
+    #[test]
+    fn rejects_positions_out_of_range() {
+        let past = |v: f64| v + v.signum() * 1e-9;
+        for (lat, lon) in [
+            (past(90.0), 0.0),
+            (past(-90.0), 0.0),
+            (0.0, past(180.0)),
+            (0.0, past(-180.0)),
+            (999.0, 16.7),
+            (f64::NAN, 16.7),
+            (52.5, f64::NAN),
+            (f64::INFINITY, 16.7),
+            (f64::MIN_POSITIVE / 2.0, 16.7),
+        ] {
+            assert!(check_position(lat, lon).is_err(), "{lat}, {lon}");
+        }
+    }
+}
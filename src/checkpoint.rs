@@ -0,0 +1,107 @@
+//! Filter state saved by the processor so that a restart can resume tracking.
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use geoconv::{Degrees, Lle, Meters, Wgs84};
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::ekf::{Ekf, Real};
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Seconds since the UNIX epoch when the checkpoint was written.
+    pub saved_at: f64,
+    /// ENU reference the filter states are relative to.
+    pub ref_lat: f64,
+    pub ref_lon: f64,
+    pub tracks: Vec<TrackState>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrackState {
+    pub track: u32,
+    pub x_est: Vec<Real>,
+    /// `P_est` in column-major order.
+    pub P_est: Vec<Real>,
+}
+
+impl Checkpoint {
+    pub fn new(ref_lle: &Lle<Wgs84>, tracks: &HashMap<u32, Ekf>) -> Self {
+        Self {
+            saved_at: unix_time(SystemTime::now()),
+            ref_lat: ref_lle.latitude.as_float(),
+            ref_lon: ref_lle.longitude.as_float(),
+            tracks: tracks
+                .iter()
+                .map(|(&track, ekf)| TrackState {
+                    track,
+                    x_est: ekf.x_est.as_slice().to_vec(),
+                    P_est: ekf.P_est.as_slice().to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the checkpoint to a temporary file next to `path` and renames it over `path`, so
+    /// that a crash mid-write leaves the previous checkpoint intact.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string(self).map_err(io::Error::other)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Reads the checkpoint at `path`, or `None` if there is none or it is older than `max_age`.
+    pub fn load(path: &Path, max_age: Duration) -> Option<Self> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("Failed to read checkpoint {}: {err}", path.display());
+                return None;
+            }
+        };
+        let checkpoint: Checkpoint = match serde_json::from_str(&json) {
+            Ok(checkpoint) => checkpoint,
+            Err(err) => {
+                log::warn!("Ignoring malformed checkpoint {}: {err}", path.display());
+                return None;
+            }
+        };
+        let age = unix_time(SystemTime::now()) - checkpoint.saved_at;
+        if age > max_age.as_secs_f64() {
+            log::info!("Ignoring checkpoint {} saved {age:.1} s ago", path.display());
+            return None;
+        }
+        Some(checkpoint)
+    }
+
+    pub fn ref_lle(&self) -> Lle<Wgs84> {
+        Lle::new(Degrees::new(self.ref_lat), Degrees::new(self.ref_lon), Meters::new(0.0))
+    }
+}
+
+impl TrackState {
+    /// Copies the saved state into `ekf`, failing if it was saved with a different state layout.
+    pub fn restore(&self, ekf: &mut Ekf) -> Result<(), String> {
+        let n = ekf.x_est.len();
+        if self.x_est.len() != n || self.P_est.len() != n * n {
+            return Err(format!("expected a state of {n} entries, got {}", self.x_est.len()));
+        }
+        ekf.x_est = DVector::from_column_slice(&self.x_est);
+        ekf.P_est = DMatrix::from_column_slice(n, n, &self.P_est);
+        ekf.seeded = true;
+        Ok(())
+    }
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
@@ -1,94 +1,1596 @@
-use geoconv::Enu;
-use nalgebra::{DMatrix, DVector, Matrix4, Vector4};
+#[cfg(feature = "f32")]
+use std::f32::consts::{PI, TAU};
+#[cfg(not(feature = "f32"))]
+use std::f64::consts::{PI, TAU};
 
-const INIT_POS_STDDEV: f64 = 1500.0;
-const INIT_VEL_STDDEV: f64 = 15.0;
-const PROCESS_NOISE_STDDEV: f64 = 1.5;
-const MEASUREMENT_STDDEV: f64 = 150.0;
+use geoconv::{Enu, Meters};
+use nalgebra::{DMatrix, DVector, Vector2};
+
+/// Scalar type of the filter, single precision with the `f32` feature for targets where it is
+/// much faster. Positions from `geoconv` are converted at the boundary and stay `f64` there.
+#[cfg(not(feature = "f32"))]
+pub type Real = f64;
+#[cfg(feature = "f32")]
+pub type Real = f32;
+
+pub const INIT_POS_STDDEV: Real = 1500.0;
+pub const INIT_VEL_STDDEV: Real = 15.0;
+pub const PROCESS_NOISE_STDDEV: Real = 1.5;
+pub const MEASUREMENT_STDDEV: Real = 150.0;
+/// Default standard deviation of a bearing measurement, about 2 degrees, in radians.
+pub const BEARING_STDDEV: Real = 0.035;
+/// Default standard deviation of [`EkfConfig::expected_altitude`], in meters.
+pub const EXPECTED_ALTITUDE_STDDEV: Real = 5.0;
+pub const INIT_ACC_STDDEV: Real = 5.0;
+/// Default [`EkfConfig::range_epsilon`], in meters.
+pub const RANGE_EPSILON: Real = 1e-6;
+/// Range of time steps accepted by [`Ekf::predict`] callers, in seconds.
+pub const MIN_DT: Real = 1e-3;
+pub const MAX_DT: Real = 1.0;
+const STRICT_MAX_COV_TRACE: Real = 1e10;
+/// Largest relative asymmetry of `P_est` accepted by [`Ekf::check_consistency`], loose enough
+/// for the rounding of single precision with the `f32` feature.
+const STRICT_SYMMETRY_TOL: Real = if cfg!(feature = "f32") { 1e-4 } else { 1e-9 };
+/// Ratio of the smallest to the largest singular value below which [`trilaterate`] takes the
+/// sensors for collinear.
+const MIN_SINGULAR_RATIO: Real = if cfg!(feature = "f32") { 1e-5 } else { 1e-9 };
+/// Weight of the latest update in the running average of the normalized innovation squared.
+const NIS_AVERAGE_WEIGHT: Real = 0.1;
+/// Exponent of the average normalized innovation squared applied to the process noise scale on
+/// every update, below 1 to approach the matching scale without overshooting it.
+const NIS_SCALE_GAIN: Real = 0.5;
+/// Most Gauss-Newton iterations of [`Ekf::solve_wls`].
+const WLS_MAX_ITERATIONS: usize = 10;
+/// Step in meters below which [`Ekf::solve_wls`] considers its iterations converged.
+const WLS_TOLERANCE: Real = 1e-3;
+/// Smallest altitude difference between sensors, in meters, from which [`fit_altitude`] solves
+/// for the target altitude.
+const MIN_ALTITUDE_SPREAD: Real = 1.0;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Sensor {
+    /// Position of the sensor east, north and up of the reference, whatever the
+    /// [`EkfConfig::convention`] of the filter.
+    ///
+    /// Sensors are placed with `CoordinateSystem::lle_to_enu(reference, sensor)`, in that
+    /// argument order, and the filter reorders the axes into those of its state with
+    /// [`Convention::axes`], so ranges and their Jacobian are plain differences of the state and
+    /// the sensor position.
     pub enu: Enu,
-    pub dist: f64,
+    pub dist: Real,
+    /// Measurement standard deviation of this sensor, [`EkfConfig::measurement_stddev`] if `None`.
+    pub stddev: Option<Real>,
+    /// Longest range accepted from this sensor, in meters, [`EkfConfig::max_dist`] if `None`.
+    pub max_dist: Option<Real>,
+    /// Horizontal angle of arrival from the sensor to the target, in radians clockwise from
+    /// north, measured in addition to or instead of the range (with `dist` 0).
+    pub bearing: Option<Real>,
+}
+
+impl Sensor {
+    /// East, north and up coordinates of the sensor, in the order of the state components.
+    pub fn position(&self) -> [Real; 3] {
+        [
+            self.enu.east.as_float() as Real,
+            self.enu.north.as_float() as Real,
+            self.enu.up.as_float() as Real,
+        ]
+    }
+
+    /// Sensor with its slant range to a target at `alt` converted into a horizontal range.
+    ///
+    /// The range is left untouched if it is shorter than the height difference.
+    pub fn horizontal(&self, alt: Real) -> Sensor {
+        let dz = alt - self.position()[2];
+        if self.dist < dz.abs() {
+            return *self;
+        }
+        Sensor {
+            dist: (self.dist.powi(2) - dz.powi(2)).sqrt(),
+            ..*self
+        }
+    }
+}
+
+/// Matrices formed by the most recent call to [`Ekf::update`].
+#[derive(Clone, Debug, Default)]
+pub struct UpdateInfo {
+    /// Indices of the sensors used in the update, or that passed filtering if it was skipped.
+    pub used: Vec<usize>,
+    /// Indices of the sensors rejected because their range was too far from the predicted one,
+    /// either by `gate_meters` or by the chi-square gate.
+    pub gated: Vec<usize>,
+    /// Indices of the sensors merged into a coincident sensor listed in `used`, see
+    /// [`EkfConfig::merge_distance`].
+    pub merged: Vec<usize>,
+    /// Indices of the sensors in `used` whose bearing was used in the update.
+    pub bearings: Vec<usize>,
+    /// Whether [`EkfConfig::expected_altitude`] was used in the update.
+    pub altitude: bool,
+    /// Measurement Jacobian, present only when a full update was applied.
+    ///
+    /// Its rows are the ranges of the sensors in `used` that have one, followed by the bearings
+    /// of the sensors in `bearings` and the expected altitude if `altitude` is set, and the same
+    /// goes for `R` and `innovation`.
+    pub H: Option<DMatrix<Real>>,
+    /// Measurement noise covariance, present only when a full update was applied.
+    pub R: Option<DMatrix<Real>>,
+    /// Log-likelihood of the innovation under its predicted covariance `S`.
+    pub log_likelihood: Option<Real>,
+    /// Innovation `z - h(x_pred)`, in meters for ranges (squared meters with
+    /// [`RangeModel::SquaredRange`]) and in radians for bearings, present only when a full update
+    /// was applied.
+    pub innovation: Option<DVector<Real>>,
+    /// Index of the sensor measured by each row of `H`, `R` and `innovation`, `None` for the
+    /// expected altitude and for the east and north of the intersection of two ranges, see
+    /// [`EkfConfig::two_sensor_noise_factor`].
+    pub rows: Vec<Option<usize>>,
+}
+
+/// Kinematic model of the drone between updates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MotionModel {
+    /// Position and velocity, driven by random acceleration
+    #[default]
+    ConstantVelocity,
+    /// Position, velocity and acceleration, driven by random jerk
+    ConstantAcceleration,
+}
+
+impl MotionModel {
+    /// Number of state entries per position axis.
+    pub fn order(self) -> usize {
+        match self {
+            MotionModel::ConstantVelocity => 2,
+            MotionModel::ConstantAcceleration => 3,
+        }
+    }
+}
+
+/// Form in which ranges enter the measurement update.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RangeModel {
+    /// The range itself, whose Jacobian is undefined with the target over a sensor
+    #[default]
+    Range,
+    /// The squared range, smooth everywhere, with the noise propagated from the range noise
+    SquaredRange,
+    /// Time difference of arrival: the range of each sensor minus the range of the reference
+    /// sensor, see [`EkfConfig::tdoa_reference`], with the noise of the reference range shared
+    /// by every difference
+    Tdoa,
+}
+
+/// Axes of the position state of the filter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Convention {
+    /// East, north and up
+    #[default]
+    Enu,
+    /// North, east and down
+    Ned,
+}
+
+impl Convention {
+    /// East, north and up coordinates reordered into the axes of the convention, or coordinates
+    /// in the axes of the convention back into east, north and up, the map being its own inverse.
+    pub fn axes(self, [a, b, c]: [Real; 3]) -> [Real; 3] {
+        match self {
+            Convention::Enu => [a, b, c],
+            Convention::Ned => [b, a, -c],
+        }
+    }
+
+    /// Indices of the east and north components of a position in the axes of the convention.
+    fn east_north(self) -> (usize, usize) {
+        match self {
+            Convention::Enu => (0, 1),
+            Convention::Ned => (1, 0),
+        }
+    }
+
+    /// Sign of the up direction along the third axis.
+    fn up(self) -> Real {
+        match self {
+            Convention::Enu => 1.0,
+            Convention::Ned => -1.0,
+        }
+    }
 }
 
+/// Noise parameters of the filter.
+#[derive(Clone, Copy, Debug)]
+pub struct EkfConfig {
+    /// Axes of the position state, and so of [`Ekf::x_est`], [`Ekf::P_est`] and the columns of
+    /// [`UpdateInfo::H`]. Positions passed to and returned by the methods of [`Ekf`] are east,
+    /// north and up either way.
+    pub convention: Convention,
+    /// State layout and kinematics used by the prediction step.
+    pub motion_model: MotionModel,
+    /// Initial standard deviation of each position component, in meters.
+    pub init_pos_stddev: Real,
+    /// Initial standard deviation of each velocity component, in m/s.
+    pub init_vel_stddev: Real,
+    /// Initial standard deviation of each acceleration component, in m/s^2.
+    pub init_acc_stddev: Real,
+    /// Standard deviation of the acceleration (m/s^2) or, with the constant-acceleration model,
+    /// of the jerk (m/s^3) driving the process noise.
+    pub process_noise_stddev: Real,
+    /// Default standard deviation of a range measurement, in meters.
+    pub measurement_stddev: Real,
+    /// Form of the range measurement equation. Ranges are given in meters either way.
+    pub range_model: RangeModel,
+    /// Floor of the predicted range, in meters, keeping the range Jacobian finite with the
+    /// estimate on a sensor. A larger floor damps the swing of the Jacobian as the drone flies
+    /// over a module.
+    pub range_epsilon: Real,
+    /// Index, among the sensors passed to [`Ekf::update`], of the sensor the ranges of the others
+    /// are relative to with [`RangeModel::Tdoa`]. Its own `dist` is ignored.
+    pub tdoa_reference: usize,
+    /// Ignore ranges longer than this, in meters, unless the sensor sets its own
+    /// [`Sensor::max_dist`].
+    pub max_dist: Option<Real>,
+    /// Reject ranges whose normalized innovation squared exceeds this chi-square threshold.
+    pub chi2_gate: Option<Real>,
+    /// Clamp the horizontal speed to this after every update, in m/s.
+    pub max_speed: Option<Real>,
+    /// With exactly two ranges, update the horizontal position from the intersection of their
+    /// circles nearest to the prediction, with the range noise inflated by this factor.
+    pub two_sensor_noise_factor: Option<Real>,
+    /// Start from a closed-form trilateration of the first ranges instead of the initial position.
+    pub seed: bool,
+    /// Scale the process noise by up to this factor while the innovations are larger than
+    /// expected, as when the drone maneuvers, see [`Ekf::process_noise_scale`].
+    pub max_process_noise_scale: Option<Real>,
+    /// Standard deviation of a bearing measurement, in radians.
+    pub bearing_stddev: Real,
+    /// Altitude the drone is expected to fly at, in meters, added to every 3D update as a
+    /// measurement of the altitude state. Ignored in 2D mode.
+    pub expected_altitude: Option<Real>,
+    /// Standard deviation of `expected_altitude`, in meters.
+    pub expected_altitude_stddev: Real,
+    /// Merge sensors closer than this to each other, in meters, into one at their mean position
+    /// with their mean range, so that a sensor reported twice does not count twice.
+    pub merge_distance: Option<Real>,
+}
+
+impl Default for EkfConfig {
+    fn default() -> Self {
+        Self {
+            convention: Convention::default(),
+            motion_model: MotionModel::default(),
+            init_pos_stddev: INIT_POS_STDDEV,
+            init_vel_stddev: INIT_VEL_STDDEV,
+            init_acc_stddev: INIT_ACC_STDDEV,
+            process_noise_stddev: PROCESS_NOISE_STDDEV,
+            measurement_stddev: MEASUREMENT_STDDEV,
+            range_model: RangeModel::default(),
+            range_epsilon: RANGE_EPSILON,
+            tdoa_reference: 0,
+            max_dist: None,
+            chi2_gate: None,
+            max_speed: None,
+            two_sensor_noise_factor: None,
+            seed: true,
+            max_process_noise_scale: None,
+            bearing_stddev: BEARING_STDDEV,
+            expected_altitude: None,
+            expected_altitude_stddev: EXPECTED_ALTITUDE_STDDEV,
+            merge_distance: None,
+        }
+    }
+}
+
+/// EKF over ranges, and optionally bearings, from fixed sensors.
+///
+/// The state is `[x, y, vx, vy]` in 2D mode and `[x, y, z, vx, vy, vz]` in 3D mode, followed by
+/// the acceleration components with the constant-acceleration model, along the axes of
+/// [`EkfConfig::convention`].
 pub struct Ekf {
-    pub x_est: Vector4<f64>,
-    pub P_est: Matrix4<f64>,
-    pub F: Box<dyn Fn(f64) -> Matrix4<f64>>,
-    pub Q: Box<dyn Fn(f64) -> Matrix4<f64>>,
-    pub max_dist: Option<f64>,
+    pub x_est: DVector<Real>,
+    pub P_est: DMatrix<Real>,
+    pub F: Box<dyn Fn(Real) -> DMatrix<Real>>,
+    pub Q: Box<dyn Fn(Real) -> DMatrix<Real>>,
+    pub config: EkfConfig,
+    /// Nominal drone altitude used to convert slant ranges into horizontal ranges in 2D mode.
+    pub drone_alt: Option<Real>,
+    /// Maximum number of sensors dropped to make a singular innovation covariance invertible.
+    pub singular_retries: usize,
+    /// Number of sensors dropped so far because of a singular innovation covariance.
+    pub singular_retry_count: usize,
+    /// Check the filter invariants after every update and log any violation.
+    pub strict: bool,
+    /// Reject ranges further than this from the predicted range, in meters.
+    ///
+    /// The gate only applies once the predicted position is known to within the gate.
+    pub gate_meters: Option<Real>,
+    /// Whether the position has been seeded by trilateration, see [`EkfConfig::seed`].
+    pub seeded: bool,
+    /// Factor `Q` is multiplied by in the prediction, between 1 and
+    /// [`EkfConfig::max_process_noise_scale`].
+    pub process_noise_scale: Real,
+    /// Running average of the normalized innovation squared per range.
+    nis_average: Option<Real>,
+    pub last_update: UpdateInfo,
 }
 
 impl Ekf {
-    pub fn new(x: f64, y: f64, max_dist: Option<f64>) -> Self {
-        let x_est = Vector4::new(x, y, 0.0, 0.0);
-        let P_est = Matrix4::from_diagonal(&Vector4::new(
-            INIT_POS_STDDEV.powi(2),
-            INIT_POS_STDDEV.powi(2),
-            INIT_VEL_STDDEV.powi(2),
-            INIT_VEL_STDDEV.powi(2),
-        ));
+    /// Filter with the default configuration at `x` east and `y` north, accepting ranges up to
+    /// `max_dist`.
+    pub fn new(x: Real, y: Real, max_dist: Option<Real>) -> Self {
+        Self::with_config(
+            x,
+            y,
+            EkfConfig {
+                max_dist,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Filter at `x` east and `y` north.
+    pub fn with_config(x: Real, y: Real, config: EkfConfig) -> Self {
+        Self::with_position(&[x, y], config)
+    }
+
+    /// Filter at `x` east, `y` north and `z` up that also estimates altitude from the vertical
+    /// component of the ranges.
+    ///
+    /// `z` should differ from the sensor altitudes, where the ranges carry no vertical gradient.
+    pub fn new_3d(x: Real, y: Real, z: Real, config: EkfConfig) -> Self {
+        Self::with_position(&[x, y, z], config)
+    }
+
+    fn with_position(pos: &[Real], config: EkfConfig) -> Self {
+        let dims = pos.len();
+        let order = config.motion_model.order();
+        let n = order * dims;
+        let pos = config
+            .convention
+            .axes([pos[0], pos[1], pos.get(2).copied().unwrap_or(0.0)]);
+        let x_est = DVector::from_iterator(
+            n,
+            pos[..dims]
+                .iter()
+                .copied()
+                .chain(std::iter::repeat_n(0.0, n - dims)),
+        );
+        let P_est = initial_covariance(dims, &config);
         // let F = Matrix4::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0);
-        let F = Box::new(|dt: f64| {
-            Matrix4::new(
-                1.0, 0.0, dt, 0.0, 0.0, 1.0, 0.0, dt, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
-            )
+        let F = Box::new(move |dt: Real| {
+            let mut F = DMatrix::identity(n, n);
+            for i in 0..dims {
+                F[(i, dims + i)] = dt;
+                if order == 3 {
+                    F[(i, 2 * dims + i)] = dt * dt / 2.0;
+                    F[(dims + i, 2 * dims + i)] = dt;
+                }
+            }
+            F
         });
-        let Q = Box::new(|dt: f64| {
-            let q_pos = (PROCESS_NOISE_STDDEV * dt * dt / 2.0).powi(2);
-            let q_vel = (PROCESS_NOISE_STDDEV * dt).powi(2);
-            Matrix4::from_diagonal(&Vector4::new(q_pos, q_pos, q_vel, q_vel))
+        let process_noise = config.process_noise_stddev;
+        let Q = Box::new(move |dt: Real| {
+            let q = match order {
+                3 => [
+                    (process_noise * dt * dt * dt / 6.0).powi(2),
+                    (process_noise * dt * dt / 2.0).powi(2),
+                    (process_noise * dt).powi(2),
+                ],
+                _ => [
+                    (process_noise * dt * dt / 2.0).powi(2),
+                    (process_noise * dt).powi(2),
+                    0.0,
+                ],
+            };
+            DMatrix::from_diagonal(&DVector::from_iterator(
+                n,
+                q[..order]
+                    .iter()
+                    .flat_map(|&q| std::iter::repeat_n(q, dims)),
+            ))
         });
 
-        Self { x_est, P_est, F, Q, max_dist }
+        Self {
+            x_est,
+            P_est,
+            F,
+            Q,
+            config,
+            drone_alt: None,
+            singular_retries: 0,
+            singular_retry_count: 0,
+            strict: false,
+            gate_meters: None,
+            seeded: !config.seed,
+            process_noise_scale: 1.0,
+            nis_average: None,
+            last_update: UpdateInfo::default(),
+        }
+    }
+
+    /// Reinitializes the state at `x` east and `y` north at rest with the initial covariance.
+    ///
+    /// The configuration, `F` and `Q` are kept, as is the altitude in 3D mode. The position is
+    /// seeded again on the next update if [`EkfConfig::seed`] is set.
+    pub fn reset(&mut self, x: Real, y: Real) {
+        let dims = self.dims();
+        let z = if dims == 3 { self.x_est[2] } else { 0.0 };
+        let (east, north) = self.config.convention.east_north();
+        self.x_est.fill(0.0);
+        self.x_est[east] = x;
+        self.x_est[north] = y;
+        if dims == 3 {
+            self.x_est[2] = z;
+        }
+        self.P_est = initial_covariance(dims, &self.config);
+        self.seeded = !self.config.seed;
+        self.process_noise_scale = 1.0;
+        self.nis_average = None;
+        self.last_update = UpdateInfo::default();
+    }
+
+    /// Number of position axes, 2 or 3.
+    pub fn dims(&self) -> usize {
+        self.x_est.len() / self.config.motion_model.order()
+    }
+
+    /// Names of the state entries in order, e.g. `east`, `north`, `vel_east`, `vel_north`.
+    pub fn state_names(&self) -> Vec<String> {
+        let axes = match self.config.convention {
+            Convention::Enu => ["east", "north", "up"],
+            Convention::Ned => ["north", "east", "down"],
+        };
+        let axes = &axes[..self.dims()];
+        ["", "vel_", "acc_"][..self.config.motion_model.order()]
+            .iter()
+            .flat_map(|prefix| axes.iter().map(move |axis| format!("{prefix}{axis}")))
+            .collect()
+    }
+
+    /// Estimated altitude, always 0 in 2D mode.
+    pub fn altitude(&self) -> Real {
+        if self.dims() == 3 {
+            self.config.convention.up() * self.x_est[2]
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated east and north position, in meters.
+    pub fn east_north(&self) -> (Real, Real) {
+        let (east, north) = self.config.convention.east_north();
+        (self.x_est[east], self.x_est[north])
     }
 
-    pub fn predict(&self, dt: f64) -> (Vector4<f64>, Matrix4<f64>) {
+    /// Standard deviations of the east and north position estimates, in meters.
+    pub fn position_stddev(&self) -> (Real, Real) {
+        let (east, north) = self.config.convention.east_north();
+        (self.P_est[(east, east)].sqrt(), self.P_est[(north, north)].sqrt())
+    }
+
+    /// Normalized estimation error squared of the horizontal position against a true position,
+    /// chi-square distributed with 2 degrees of freedom when the covariance is consistent.
+    pub fn position_nees(&self, east: Real, north: Real) -> Option<Real> {
+        let truth = self.config.convention.axes([east, north, 0.0]);
+        let error = Vector2::new(self.x_est[0] - truth[0], self.x_est[1] - truth[1]);
+        let P_inv = self
+            .P_est
+            .fixed_view::<2, 2>(0, 0)
+            .clone_owned()
+            .try_inverse()?;
+        Some((error.transpose() * P_inv * error)[(0, 0)])
+    }
+
+    /// Estimated east and north velocity, in m/s.
+    pub fn velocity(&self) -> (Real, Real) {
+        let dims = self.dims();
+        let (east, north) = self.config.convention.east_north();
+        (self.x_est[dims + east], self.x_est[dims + north])
+    }
+
+    /// Range from `sensor` to the current position estimate.
+    pub fn range_to(&self, sensor: &Sensor) -> Real {
+        let s = self.position_of(sensor);
+        range(&self.x_est, self.dims(), &s, self.config.range_epsilon)
+    }
+
+    /// Position of `sensor` in the axes of the state.
+    fn position_of(&self, sensor: &Sensor) -> [Real; 3] {
+        self.config.convention.axes(sensor.position())
+    }
+
+    pub fn predict(&self, dt: Real) -> (DVector<Real>, DMatrix<Real>) {
         let F = (self.F)(dt);
-        let Q = (self.Q)(dt);
-        (F * self.x_est, F * self.P_est * F.transpose() + Q)
+        let Q = (self.Q)(dt) * self.process_noise_scale;
+        (&F * &self.x_est, &F * &self.P_est * F.transpose() + Q)
+    }
+
+    pub fn update(&mut self, mut x_pred: DVector<Real>, P_pred: DMatrix<Real>, sensors: &[Sensor]) {
+        if !self.seeded {
+            self.seed(&mut x_pred, sensors);
+        }
+        self.apply_update(x_pred, P_pred, sensors);
+        if let Some(max_speed) = self.config.max_speed {
+            self.clamp_speed(max_speed);
+        }
+        if self.strict
+            && let Err(err) = self.check_consistency()
+        {
+            log::error!(
+                "Filter consistency violated: {err}\nx_est ={}\nP_est ={}\nsensors = {sensors:?}",
+                self.x_est,
+                self.P_est
+            );
+        }
     }
 
-    pub fn update(&mut self, x_pred: Vector4<f64>, P_pred: Matrix4<f64>, sensors: &[Sensor]) {
-        let filtered_sensors: Vec<Sensor> = sensors
+    /// Replaces the position with a weighted least-squares fix from the ranges of `sensors`
+    /// alone, without the motion model or the previous estimate.
+    ///
+    /// Gauss-Newton iterations start from [`trilaterate`] and weight each range by the inverse
+    /// of its variance in `R`. The position covariance is that of the fix, `(H^T R^-1 H)^-1`, and
+    /// the velocity is reset to 0 with its initial covariance. Without 3 ranges or with collinear
+    /// sensors the previous fix is kept. Bearings and the gates are not used, and neither are
+    /// range differences, so with [`RangeModel::Tdoa`] there is never a fix.
+    pub fn solve_wls(&mut self, sensors: &[Sensor]) {
+        if self.config.range_model == RangeModel::Tdoa {
+            self.last_update = UpdateInfo::default();
+            return;
+        }
+        let dims = self.dims();
+        let alt = if dims == 3 {
+            Some(self.altitude())
+        } else {
+            self.drone_alt
+        };
+        let used: Vec<usize> = (0..sensors.len())
+            .filter(|&i| {
+                let s = &sensors[i];
+                s.dist > 0.0
+                    && s.max_dist
+                        .or(self.config.max_dist)
+                        .is_none_or(|max| s.dist <= max)
+            })
+            .collect();
+        let filtered_sensors: Vec<Sensor> = used
+            .iter()
+            .map(|&i| match alt {
+                Some(alt) => sensors[i].horizontal(alt),
+                None => sensors[i],
+            })
+            .collect();
+        let (used, filtered_sensors, merged) = match self.config.merge_distance {
+            Some(distance) => merge_coincident(used, filtered_sensors, distance),
+            None => (used, filtered_sensors, Vec::new()),
+        };
+        self.last_update = UpdateInfo {
+            used,
+            merged,
+            ..Default::default()
+        };
+        let Some((x, y)) = trilaterate(&filtered_sensors) else {
+            return;
+        };
+
+        let n = filtered_sensors.len();
+        let R = DMatrix::from_diagonal(&DVector::from_iterator(
+            n,
+            filtered_sensors
+                .iter()
+                .map(|s| s.stddev.unwrap_or(self.config.measurement_stddev).powi(2)),
+        ));
+        let W = DMatrix::from_diagonal(&R.diagonal().map(|variance| 1.0 / variance));
+        let mut position = Vector2::new(x, y);
+        let mut G = DMatrix::zeros(n, 2);
+        let mut residual = DVector::zeros(n);
+        let mut covariance = None;
+        for _ in 0..WLS_MAX_ITERATIONS {
+            for (i, sensor) in filtered_sensors.iter().enumerate() {
+                let s = sensor.position();
+                let (dx, dy) = (position[0] - s[0], position[1] - s[1]);
+                let dist = dx.hypot(dy).max(self.config.range_epsilon);
+                G[(i, 0)] = dx / dist;
+                G[(i, 1)] = dy / dist;
+                residual[i] = sensor.dist - dist;
+            }
+            let G_t_W = G.transpose() * &W;
+            let Some(P) = invert(&G_t_W * &G) else {
+                return;
+            };
+            let step = &P * (&G_t_W * &residual);
+            position += Vector2::new(step[0], step[1]);
+            covariance = Some(P);
+            if step.norm() < WLS_TOLERANCE {
+                break;
+            }
+        }
+        let Some(P) = covariance else {
+            return;
+        };
+
+        // the fix is east and north, the altitude in 3D mode is left as it was
+        let axes = <[usize; 2]>::from(self.config.convention.east_north());
+        self.x_est.rows_mut(dims, self.x_est.len() - dims).fill(0.0);
+        for (k, &i) in axes.iter().enumerate() {
+            self.x_est[i] = position[k];
+        }
+        let previous = std::mem::replace(&mut self.P_est, initial_covariance(dims, &self.config));
+        if dims == 3 {
+            self.P_est[(2, 2)] = previous[(2, 2)];
+        }
+        let mut H = DMatrix::zeros(n, self.x_est.len());
+        for (k, &i) in axes.iter().enumerate() {
+            for (l, &j) in axes.iter().enumerate() {
+                self.P_est[(i, j)] = P[(k, l)];
+            }
+            H.column_mut(i).copy_from(&G.column(k));
+        }
+        self.last_update.H = Some(H);
+        self.last_update.R = Some(R);
+        self.last_update.innovation = Some(residual);
+        self.last_update.rows = self.last_update.used.iter().map(|&i| Some(i)).collect();
+    }
+
+    /// Moves the predicted position to the trilateration of `sensors`, if they determine one.
+    ///
+    /// Range differences do not trilaterate, so with [`RangeModel::Tdoa`] the filter starts from
+    /// its initial position.
+    fn seed(&mut self, x_pred: &mut DVector<Real>, sensors: &[Sensor]) {
+        if self.config.range_model == RangeModel::Tdoa {
+            return;
+        }
+        let alt = if self.dims() == 3 {
+            Some(self.config.convention.up() * x_pred[2])
+        } else {
+            self.drone_alt
+        };
+        let sensors: Vec<Sensor> = sensors
             .iter()
             .filter(|s| {
-                s.dist > 0.0 && (self.max_dist.is_none() || s.dist <= self.max_dist.unwrap())
+                s.dist > 0.0
+                    && s.max_dist
+                        .or(self.config.max_dist)
+                        .is_none_or(|max| s.dist <= max)
+            })
+            .map(|s| match alt {
+                Some(alt) => s.horizontal(alt),
+                None => *s,
+            })
+            .collect();
+        if let Some((x, y)) = trilaterate(&sensors) {
+            log::debug!("Seeding the filter at ({x:.1}, {y:.1})");
+            let (east, north) = self.config.convention.east_north();
+            x_pred[east] = x;
+            x_pred[north] = y;
+            self.seeded = true;
+        }
+    }
+
+    /// Moves [`Self::process_noise_scale`] towards the level at which the average normalized
+    /// innovation squared per range matches its expected value of 1.
+    ///
+    /// Larger innovations than predicted mean the motion model lags behind the drone, so the
+    /// process noise is raised, and lowered back towards the configured `Q` once they are small.
+    fn adapt_process_noise(&mut self, nis_per_range: Real, max_scale: Real) {
+        let average = match self.nis_average {
+            Some(average) => average + NIS_AVERAGE_WEIGHT * (nis_per_range - average),
+            None => nis_per_range,
+        };
+        self.nis_average = Some(average);
+        self.process_noise_scale = (self.process_noise_scale * average.powf(NIS_SCALE_GAIN))
+            .clamp(1.0, max_scale.max(1.0));
+    }
+
+    /// Scales the horizontal velocity down to `max_speed` and its covariance along with it.
+    fn clamp_speed(&mut self, max_speed: Real) {
+        let (east, north) = self.velocity();
+        let speed = east.hypot(north);
+        if speed <= max_speed {
+            return;
+        }
+        let scale = max_speed / speed;
+        let dims = self.dims();
+        for i in [dims, dims + 1] {
+            self.x_est[i] *= scale;
+            // the same as T P T^T with T scaling the velocity components
+            self.P_est.row_mut(i).scale_mut(scale);
+            self.P_est.column_mut(i).scale_mut(scale);
+        }
+        log::debug!("Clamped speed from {speed:.1} to {max_speed:.1} m/s");
+    }
+
+    /// Horizontal dilution of precision of the sensor geometry in the last measurement update, or
+    /// `None` without one or if the geometry does not determine the position.
+    pub fn hdop(&self) -> Option<Real> {
+        let H = self.last_update.H.as_ref()?;
+        let ranges =
+            H.nrows() - self.last_update.bearings.len() - self.last_update.altitude as usize;
+        let mut G = H.view((0, 0), (ranges, self.dims())).into_owned();
+        if self.config.range_model == RangeModel::SquaredRange {
+            // back to unit line-of-sight rows, the squared range scales them by twice the range
+            for mut row in G.row_iter_mut() {
+                let norm = row.norm();
+                if norm > 0.0 {
+                    row /= norm;
+                }
+            }
+        }
+        horizontal_dop(&G)
+    }
+
+    /// Altitude fitted by [`fit_altitude`] to the ranges of the sensors used in the last
+    /// measurement update, given as the `sensors` passed to [`Self::update`].
+    pub fn fitted_altitude(&self, sensors: &[Sensor]) -> Option<Real> {
+        self.last_update.H.as_ref()?;
+        if self.config.range_model == RangeModel::Tdoa {
+            return None;
+        }
+        let used: Vec<Sensor> = self
+            .last_update
+            .used
+            .iter()
+            .map(|&i| sensors[i])
+            .filter(|s| s.dist > 0.0)
+            .collect();
+        let (east, north) = self.east_north();
+        fit_altitude(east, north, &used)
+    }
+
+    /// Checks that `x_est` is finite and `P_est` is symmetric, positive-definite and bounded.
+    pub fn check_consistency(&self) -> Result<(), String> {
+        if self.x_est.iter().any(|v| !v.is_finite()) {
+            return Err("x_est is not finite".to_owned());
+        }
+        let asymmetry = (&self.P_est - self.P_est.transpose()).amax();
+        if asymmetry > STRICT_SYMMETRY_TOL * self.P_est.amax().max(1.0) {
+            return Err(format!("P_est is not symmetric (max difference {asymmetry:e})"));
+        }
+        if self.P_est.clone().cholesky().is_none() {
+            return Err("P_est is not positive-definite".to_owned());
+        }
+        let trace = self.P_est.trace();
+        if !(trace > 0.0 && trace <= STRICT_MAX_COV_TRACE) {
+            return Err(format!("P_est trace {trace:e} is out of bounds"));
+        }
+        Ok(())
+    }
+
+    fn apply_update(&mut self, x_pred: DVector<Real>, P_pred: DMatrix<Real>, sensors: &[Sensor]) {
+        let dims = self.dims();
+        let epsilon = self.config.range_epsilon;
+        let reference = match self.config.range_model {
+            RangeModel::Tdoa => match sensors.get(self.config.tdoa_reference) {
+                Some(&reference) => Some(reference),
+                None => {
+                    self.last_update = UpdateInfo::default();
+                    self.x_est = x_pred;
+                    self.P_est = P_pred;
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let has_range = |i: usize| {
+            let s = &sensors[i];
+            if reference.is_some() {
+                // a difference may be negative, and the reference has none
+                return s.dist != 0.0 && i != self.config.tdoa_reference;
+            }
+            let max_dist = s.max_dist.or(self.config.max_dist);
+            s.dist > 0.0 && (max_dist.is_none() || s.dist <= max_dist.unwrap())
+        };
+        let used: Vec<usize> = (0..sensors.len())
+            .filter(|&i| has_range(i) || sensors[i].bearing.is_some())
+            .collect();
+        let filtered_sensors: Vec<Sensor> = used
+            .iter()
+            .map(|&i| {
+                // a sensor kept for its bearing alone has no range
+                let sensor = if has_range(i) {
+                    sensors[i]
+                } else {
+                    Sensor {
+                        dist: 0.0,
+                        ..sensors[i]
+                    }
+                };
+                match self.drone_alt {
+                    Some(alt) if dims == 2 && reference.is_none() => sensor.horizontal(alt),
+                    _ => sensor,
+                }
             })
-            .copied()
             .collect();
-        let n_sensors = filtered_sensors.len();
-        if n_sensors < 3 {
+        let (used, filtered_sensors, merged) = match self.config.merge_distance {
+            Some(distance) => merge_coincident(used, filtered_sensors, distance),
+            None => (used, filtered_sensors, Vec::new()),
+        };
+
+        let mut gated = Vec::new();
+        let (used, filtered_sensors): (Vec<usize>, Vec<Sensor>) = match self.gate_meters {
+            Some(gate) if (P_pred[(0, 0)] + P_pred[(1, 1)]).sqrt() < gate => used
+                .into_iter()
+                .zip(filtered_sensors)
+                .filter(|(i, s)| {
+                    let reference_pred = reference
+                        .map_or(0.0, |r| range(&x_pred, dims, &self.position_of(&r), epsilon));
+                    let dist_pred =
+                        range(&x_pred, dims, &self.position_of(s), epsilon) - reference_pred;
+                    let keep = s.dist == 0.0 || (s.dist - dist_pred).abs() <= gate;
+                    if !keep {
+                        gated.push(*i);
+                    }
+                    keep
+                })
+                .unzip(),
+            _ => (used, filtered_sensors),
+        };
+
+        // the ranges of all sensors, then their bearings
+        let mut rows: Vec<Measurement> = (0..filtered_sensors.len())
+            .filter(|&k| filtered_sensors[k].dist > 0.0)
+            .map(Measurement::Range)
+            .chain(
+                (0..filtered_sensors.len())
+                    .filter(|&k| filtered_sensors[k].bearing.is_some())
+                    .map(Measurement::Bearing),
+            )
+            .collect();
+        self.last_update = UpdateInfo {
+            used,
+            gated,
+            merged,
+            ..Default::default()
+        };
+        if rows.len() == 2
+            && reference.is_none()
+            && rows.iter().all(|m| matches!(m, Measurement::Range(_)))
+            && self.seeded
+            && let Some(factor) = self.config.two_sensor_noise_factor
+        {
+            self.apply_two_sensor_update(x_pred, P_pred, &filtered_sensors, factor);
+            return;
+        }
+        if rows.len() < 3 {
             self.x_est = x_pred;
             self.P_est = P_pred;
             return;
         }
+        if dims == 3 && self.config.expected_altitude.is_some() {
+            rows.push(Measurement::Altitude);
+        }
 
-        let z = DVector::from_iterator(n_sensors, filtered_sensors.iter().map(|s| s.dist));
-        let mut h_x_pred = DVector::zeros(n_sensors);
-        let mut H = DMatrix::zeros(n_sensors, 4);
-        let R = DMatrix::from_diagonal_element(n_sensors, n_sensors, MEASUREMENT_STDDEV.powi(2));
-        let (px, py) = (x_pred[0], x_pred[1]);
+        let mut z = DVector::zeros(rows.len());
+        let mut h_x_pred = DVector::zeros(rows.len());
+        let mut H = DMatrix::zeros(rows.len(), x_pred.len());
+        let mut R = DMatrix::zeros(rows.len(), rows.len());
+        for (i, &row) in rows.iter().enumerate() {
+            match row {
+                Measurement::Range(k) => {
+                    let sensor = &filtered_sensors[k];
+                    let s = self.position_of(sensor);
+                    let stddev = sensor.stddev.unwrap_or(self.config.measurement_stddev);
+                    match self.config.range_model {
+                        RangeModel::Range => {
+                            let dist_pred = range(&x_pred, dims, &s, epsilon);
+                            z[i] = sensor.dist;
+                            h_x_pred[i] = dist_pred;
+                            for j in 0..dims {
+                                H[(i, j)] = (x_pred[j] - s[j]) / dist_pred;
+                            }
+                            R[(i, i)] = stddev.powi(2);
+                        }
+                        RangeModel::SquaredRange => {
+                            z[i] = sensor.dist.powi(2);
+                            h_x_pred[i] = (0..dims).map(|j| (x_pred[j] - s[j]).powi(2)).sum();
+                            for j in 0..dims {
+                                H[(i, j)] = 2.0 * (x_pred[j] - s[j]);
+                            }
+                            // variance of (d + e)^2 with e ~ N(0, stddev^2)
+                            R[(i, i)] =
+                                4.0 * sensor.dist.powi(2) * stddev.powi(2) + 2.0 * stddev.powi(4);
+                        }
+                        RangeModel::Tdoa => {
+                            let r = self.position_of(&reference.unwrap());
+                            let dist_pred = range(&x_pred, dims, &s, epsilon);
+                            let reference_pred = range(&x_pred, dims, &r, epsilon);
+                            z[i] = sensor.dist;
+                            h_x_pred[i] = dist_pred - reference_pred;
+                            for j in 0..dims {
+                                H[(i, j)] = (x_pred[j] - s[j]) / dist_pred
+                                    - (x_pred[j] - r[j]) / reference_pred;
+                            }
+                            // plus the variance of the reference range, added below
+                            R[(i, i)] = stddev.powi(2);
+                        }
+                    }
+                }
+                Measurement::Bearing(k) => {
+                    let sensor = &filtered_sensors[k];
+                    let s = self.position_of(sensor);
+                    let (east, north) = self.config.convention.east_north();
+                    let (dx, dy) = (x_pred[east] - s[east], x_pred[north] - s[north]);
+                    let bearing_pred = dx.atan2(dy);
+                    let dist_sq = (dx * dx + dy * dy).max(epsilon.powi(2));
+                    // wrapped so that the innovation is the smaller angle between the two
+                    z[i] = bearing_pred + wrap_angle(sensor.bearing.unwrap() - bearing_pred);
+                    h_x_pred[i] = bearing_pred;
+                    H[(i, east)] = dy / dist_sq;
+                    H[(i, north)] = -dx / dist_sq;
+                    R[(i, i)] = self.config.bearing_stddev.powi(2);
+                }
+                Measurement::Altitude => {
+                    let up = self.config.convention.up();
+                    z[i] = self.config.expected_altitude.unwrap();
+                    h_x_pred[i] = up * x_pred[2];
+                    H[(i, 2)] = up;
+                    R[(i, i)] = self.config.expected_altitude_stddev.powi(2);
+                }
+            }
+        }
 
-        for (i, sensor) in filtered_sensors.iter().enumerate() {
-            let (sx, sy) = (-sensor.enu.east.as_float(), -sensor.enu.north.as_float());
-            let dist_pred = ((px - sx).powi(2) + (py - sy).powi(2)).sqrt().max(1e-6);
-            h_x_pred[i] = dist_pred;
-            H[(i, 0)] = (px - sx) / dist_pred;
-            H[(i, 1)] = (py - sy) / dist_pred;
+        if let Some(reference) = reference {
+            // every difference carries the error of the reference range, correlating them
+            let variance = reference
+                .stddev
+                .unwrap_or(self.config.measurement_stddev)
+                .powi(2);
+            let ranges: Vec<usize> = (0..rows.len())
+                .filter(|&i| matches!(rows[i], Measurement::Range(_)))
+                .collect();
+            for &a in &ranges {
+                for &b in &ranges {
+                    R[(a, b)] += variance;
+                }
+            }
         }
 
-        let H_t = H.transpose();
-        let S = &H * P_pred * &H_t + R;
-        if let Some(S_inv) = S.try_inverse() {
-            let K = P_pred * H_t * S_inv;
-            let y = z - h_x_pred;
-            self.x_est = x_pred + &K * y;
-            self.P_est = (Matrix4::identity() - K * H) * P_pred;
-        } else {
+        if let Some(threshold) = self.config.chi2_gate {
+            let S = &H * &P_pred * H.transpose() + &R;
+            let y = &z - &h_x_pred;
+            let rejected: Vec<usize> = (0..z.len())
+                .filter(|&i| y[i].powi(2) / S[(i, i)] > threshold)
+                .collect();
+            log::debug!("Chi-square gated {} of {} measurements", rejected.len(), z.len());
+            for &i in rejected.iter().rev() {
+                rows.remove(i);
+                z = z.remove_row(i);
+                h_x_pred = h_x_pred.remove_row(i);
+                H = H.remove_row(i);
+                R = R.remove_row(i).remove_column(i);
+            }
+            // sensors left without a measurement count as gated
+            let measured = |k: &usize| rows.iter().any(|m| m.sensor() == Some(*k));
+            let unmeasured = (0..self.last_update.used.len()).filter(|k| !measured(k));
+            let unmeasured: Vec<usize> = unmeasured.map(|k| self.last_update.used[k]).collect();
+            self.last_update.gated.extend(unmeasured);
+            self.last_update.gated.sort_unstable();
+            if sensor_rows(&rows) < 3 {
+                self.finish_rows(&rows);
+                self.x_est = x_pred;
+                self.P_est = P_pred;
+                return;
+            }
+        }
+
+        let mut retries = 0;
+        loop {
+            let H_t = H.transpose();
+            let S = &H * &P_pred * &H_t + &R;
+            let y = &z - &h_x_pred;
+            let S_inv = if let Some(S_inv) = invert(S.clone()) {
+                let nis = y.dot(&(&S_inv * &y));
+                let n = y.len() as Real;
+                self.last_update.log_likelihood =
+                    Some(-0.5 * (nis + n * TAU.ln() + S.determinant().ln()));
+                if let Some(max_scale) = self.config.max_process_noise_scale {
+                    self.adapt_process_noise(nis / n, max_scale);
+                }
+                S_inv
+            } else if retries == self.singular_retries || sensor_rows(&rows) <= 3 {
+                let eps = Real::EPSILON * S.nrows() as Real * S.amax();
+                match S.clone().pseudo_inverse(eps) {
+                    Ok(S_inv) => {
+                        log::warn!("Degenerate sensor geometry, applying a pseudo-inverse update");
+                        S_inv
+                    }
+                    Err(_) => {
+                        self.finish_rows(&rows);
+                        self.x_est = x_pred;
+                        self.P_est = P_pred;
+                        return;
+                    }
+                }
+            } else {
+                // drop the measurement with the largest normalized residual and try again
+                let worst = (0..z.len())
+                    .max_by(|&a, &b| {
+                        let na = y[a].abs() / S[(a, a)].sqrt().max(Real::EPSILON);
+                        let nb = y[b].abs() / S[(b, b)].sqrt().max(Real::EPSILON);
+                        na.total_cmp(&nb)
+                    })
+                    .unwrap();
+                match rows.remove(worst).sensor() {
+                    Some(k) => log::warn!(
+                        "Innovation covariance singular, dropping a measurement of sensor {} \
+                         and retrying",
+                        self.last_update.used[k]
+                    ),
+                    None => log::warn!(
+                        "Innovation covariance singular, dropping the expected altitude and \
+                         retrying"
+                    ),
+                }
+                z = z.remove_row(worst);
+                h_x_pred = h_x_pred.remove_row(worst);
+                H = H.remove_row(worst);
+                R = R.remove_row(worst).remove_column(worst);
+                retries += 1;
+                self.singular_retry_count += 1;
+                continue;
+            };
+
+            self.finish_rows(&rows);
+            self.correct(x_pred, P_pred, H, R, y, S_inv);
+            return;
+        }
+    }
+
+    /// Narrows `used` down to the sensors with a measurement left in `rows` and fills `bearings`,
+    /// `altitude` and the sensor of each row.
+    fn finish_rows(&mut self, rows: &[Measurement]) {
+        let used = &self.last_update.used;
+        self.last_update.rows = rows.iter().map(|m| m.sensor().map(|k| used[k])).collect();
+        self.last_update.bearings = rows
+            .iter()
+            .filter_map(|m| match m {
+                Measurement::Bearing(k) => Some(used[*k]),
+                _ => None,
+            })
+            .collect();
+        self.last_update.altitude = rows.iter().any(|m| matches!(m, Measurement::Altitude));
+        let kept: Vec<usize> = (0..used.len())
+            .filter(|k| rows.iter().any(|m| m.sensor() == Some(*k)))
+            .map(|k| used[k])
+            .collect();
+        self.last_update.used = kept;
+    }
+
+    /// Updates the horizontal position from the intersection of the range circles of two
+    /// sensors, the one nearest to the predicted position.
+    fn apply_two_sensor_update(
+        &mut self,
+        x_pred: DVector<Real>,
+        P_pred: DMatrix<Real>,
+        sensors: &[Sensor],
+        factor: Real,
+    ) {
+        let (mut a, mut b) = (sensors[0], sensors[1]);
+        if self.dims() == 3 {
+            let alt = self.config.convention.up() * x_pred[2];
+            (a, b) = (a.horizontal(alt), b.horizontal(alt));
+        }
+        let (east, north) = self.config.convention.east_north();
+        let Some((x, y)) = nearest_intersection(&a, &b, (x_pred[east], x_pred[north])) else {
             self.x_est = x_pred;
             self.P_est = P_pred;
+            return;
+        };
+        log::info!("Only two ranges, updating from their intersection at ({x:.1}, {y:.1})");
+
+        let mut H = DMatrix::zeros(2, x_pred.len());
+        H[(0, east)] = 1.0;
+        H[(1, north)] = 1.0;
+        let default = self.config.measurement_stddev;
+        let stddev = factor * (a.stddev.unwrap_or(default) + b.stddev.unwrap_or(default)) / 2.0;
+        let R = DMatrix::identity(2, 2) * stddev.powi(2);
+        let S = &H * &P_pred * H.transpose() + &R;
+        let Some(S_inv) = S.try_inverse() else {
+            self.x_est = x_pred;
+            self.P_est = P_pred;
+            return;
+        };
+        let innovation = DVector::from_vec(vec![x - x_pred[east], y - x_pred[north]]);
+        self.last_update.rows = vec![None, None];
+        self.correct(x_pred, P_pred, H, R, innovation, S_inv);
+    }
+
+    /// Applies the Kalman gain for the innovation `y` and records the update matrices.
+    fn correct(
+        &mut self,
+        x_pred: DVector<Real>,
+        P_pred: DMatrix<Real>,
+        H: DMatrix<Real>,
+        R: DMatrix<Real>,
+        y: DVector<Real>,
+        S_inv: DMatrix<Real>,
+    ) {
+        let K = &P_pred * H.transpose() * S_inv;
+        let I = DMatrix::<Real>::identity(x_pred.len(), x_pred.len());
+        self.x_est = x_pred + &K * &y;
+        // Joseph form, keeps P_est symmetric and positive semi-definite
+        let I_KH = I - &K * &H;
+        self.P_est = &I_KH * P_pred * I_KH.transpose() + &K * &R * K.transpose();
+        self.last_update.H = Some(H);
+        self.last_update.R = Some(R);
+        self.last_update.innovation = Some(y);
+    }
+}
+
+/// Merges each group of sensors within `distance` of the first sensor of the group into one at
+/// their mean position with their mean range, keeping the index and noise of the first.
+///
+/// Returns the indices and sensors left, and the indices of the sensors merged away.
+fn merge_coincident(
+    indices: Vec<usize>,
+    sensors: Vec<Sensor>,
+    distance: Real,
+) -> (Vec<usize>, Vec<Sensor>, Vec<usize>) {
+    // members of each group, as positions in `sensors`
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, sensor) in sensors.iter().enumerate() {
+        let p = sensor.position();
+        let group = groups.iter_mut().find(|g| {
+            let q = sensors[g[0]].position();
+            (0..3).map(|k| (p[k] - q[k]).powi(2)).sum::<Real>().sqrt() <= distance
+        });
+        match group {
+            Some(group) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+
+    let mut merged = Vec::new();
+    let (mut kept_indices, mut kept_sensors) = (Vec::new(), Vec::new());
+    for group in groups {
+        let first = sensors[group[0]];
+        if group.len() > 1 {
+            let n = group.len() as Real;
+            let mean =
+                |f: fn(&Sensor) -> Real| group.iter().map(|&i| f(&sensors[i])).sum::<Real>() / n;
+            log::debug!(
+                "Merging {} coincident sensors into sensor {}",
+                group.len(),
+                indices[group[0]]
+            );
+            merged.extend(group[1..].iter().map(|&i| indices[i]));
+            kept_sensors.push(Sensor {
+                enu: Enu {
+                    east: Meters::new(mean(|s| s.enu.east.as_float() as Real) as f64),
+                    north: Meters::new(mean(|s| s.enu.north.as_float() as Real) as f64),
+                    up: Meters::new(mean(|s| s.enu.up.as_float() as Real) as f64),
+                },
+                dist: mean(|s| s.dist),
+                ..first
+            });
+        } else {
+            kept_sensors.push(first);
+        }
+        kept_indices.push(indices[group[0]]);
+    }
+    merged.sort_unstable();
+    (kept_indices, kept_sensors, merged)
+}
+
+/// Intersection of the horizontal range circles of `a` and `b` nearest to `near`, or `None` if
+/// the circles do not intersect.
+fn nearest_intersection(a: &Sensor, b: &Sensor, near: (Real, Real)) -> Option<(Real, Real)> {
+    let (pa, pb) = (a.position(), b.position());
+    let (dx, dy) = (pb[0] - pa[0], pb[1] - pa[1]);
+    let d = dx.hypot(dy);
+    if d == 0.0 || d > a.dist + b.dist || d < (a.dist - b.dist).abs() {
+        return None;
+    }
+    // distance from a to the chord between the intersections, and half the chord
+    let along = (a.dist.powi(2) - b.dist.powi(2) + d * d) / (2.0 * d);
+    let half_chord = (a.dist.powi(2) - along.powi(2)).max(0.0).sqrt();
+    let (mx, my) = (pa[0] + along * dx / d, pa[1] + along * dy / d);
+    let (ox, oy) = (-dy / d * half_chord, dx / d * half_chord);
+    [(mx + ox, my + oy), (mx - ox, my - oy)]
+        .into_iter()
+        .min_by(|p, q| {
+            let dp = (p.0 - near.0).hypot(p.1 - near.1);
+            let dq = (q.0 - near.0).hypot(q.1 - near.1);
+            dp.total_cmp(&dq)
+        })
+}
+
+/// Horizontal position that best fits the horizontal ranges of `sensors` in the least-squares
+/// sense, or `None` with fewer than 3 sensors or if they are collinear.
+///
+/// Subtracting the range equation of the first sensor from the others makes the problem linear,
+/// `2 (p_i - p_0) . x = d_0^2 - d_i^2 + |p_i|^2 - |p_0|^2`.
+pub fn trilaterate(sensors: &[Sensor]) -> Option<(Real, Real)> {
+    if sensors.len() < 3 {
+        return None;
+    }
+    let p0 = sensors[0].position();
+    let d0 = sensors[0].dist;
+    let rows = sensors.len() - 1;
+    let mut A = DMatrix::zeros(rows, 2);
+    let mut b = DVector::zeros(rows);
+    for (i, sensor) in sensors[1..].iter().enumerate() {
+        let p = sensor.position();
+        A[(i, 0)] = 2.0 * (p[0] - p0[0]);
+        A[(i, 1)] = 2.0 * (p[1] - p0[1]);
+        b[i] = d0.powi(2) - sensor.dist.powi(2) + p[0].powi(2) + p[1].powi(2)
+            - p0[0].powi(2)
+            - p0[1].powi(2);
+    }
+    let svd = A.svd(true, true);
+    let (max, min) = (svd.singular_values.max(), svd.singular_values.min());
+    if min <= MIN_SINGULAR_RATIO * max {
+        return None;
+    }
+    let x = svd.solve(&b, 0.0).ok()?;
+    Some((x[0], x[1]))
+}
+
+/// Altitude of a target at `x`, `y` that best fits the slant ranges of `sensors` in the
+/// least-squares sense, or `None` unless some sensors are at least [`MIN_ALTITUDE_SPREAD`] apart
+/// in altitude.
+///
+/// With `v_i = d_i^2 - h_i^2` the squared height of the target over sensor `i` at horizontal
+/// distance `h_i`, subtracting the equation of the first sensor makes the problem linear,
+/// `2 (z_i - z_0) z = v_0 - v_i + z_i^2 - z_0^2`. Sensors at the same altitude only determine the
+/// height up to its sign, hence the guard.
+pub fn fit_altitude(x: Real, y: Real, sensors: &[Sensor]) -> Option<Real> {
+    let (first, rest) = sensors.split_first()?;
+    let height_sq = |s: &Sensor| {
+        let p = s.position();
+        s.dist.powi(2) - (x - p[0]).powi(2) - (y - p[1]).powi(2)
+    };
+    let (z0, v0) = (first.position()[2], height_sq(first));
+    let (mut aa, mut ab, mut spread): (Real, Real, Real) = (0.0, 0.0, 0.0);
+    for sensor in rest {
+        let z = sensor.position()[2];
+        let a = 2.0 * (z - z0);
+        let b = v0 - height_sq(sensor) + z.powi(2) - z0.powi(2);
+        aa += a * a;
+        ab += a * b;
+        spread = spread.max((z - z0).abs());
+    }
+    (spread >= MIN_ALTITUDE_SPREAD).then(|| ab / aa)
+}
+
+/// `sqrt(trace((H^T H)^-1))` over the east and north components, for a Jacobian `H` of ranges
+/// with respect to position only.
+pub fn horizontal_dop(H: &DMatrix<Real>) -> Option<Real> {
+    let G = (H.transpose() * H).try_inverse()?;
+    Some((G[(0, 0)] + G[(1, 1)]).sqrt())
+}
+
+fn initial_covariance(dims: usize, config: &EkfConfig) -> DMatrix<Real> {
+    let stddevs = [
+        config.init_pos_stddev,
+        config.init_vel_stddev,
+        config.init_acc_stddev,
+    ];
+    let order = config.motion_model.order();
+    DMatrix::from_diagonal(&DVector::from_iterator(
+        order * dims,
+        stddevs[..order]
+            .iter()
+            .flat_map(|s| std::iter::repeat_n(s.powi(2), dims)),
+    ))
+}
+
+/// Predicted and filtered estimates of one step, kept for [`rts_smooth`].
+pub struct FilterStep {
+    /// State transition matrix used to predict this step from the previous one.
+    pub F: DMatrix<Real>,
+    pub x_pred: DVector<Real>,
+    pub P_pred: DMatrix<Real>,
+    pub x_est: DVector<Real>,
+    pub P_est: DMatrix<Real>,
+}
+
+/// Rauch-Tung-Striebel backward pass over the steps of a forward run.
+///
+/// Returns the smoothed state and covariance of every step. Steps whose predicted covariance
+/// cannot be inverted keep their filtered estimates.
+pub fn rts_smooth(steps: &[FilterStep]) -> Vec<(DVector<Real>, DMatrix<Real>)> {
+    let mut smoothed: Vec<(DVector<Real>, DMatrix<Real>)> = steps
+        .iter()
+        .map(|s| (s.x_est.clone(), s.P_est.clone()))
+        .collect();
+    for k in (0..steps.len().saturating_sub(1)).rev() {
+        let next = &steps[k + 1];
+        let Some(P_pred_inv) = invert(next.P_pred.clone()) else {
+            continue;
+        };
+        let C = &steps[k].P_est * next.F.transpose() * P_pred_inv;
+        let (x_next, P_next) = &smoothed[k + 1];
+        let x = &steps[k].x_est + &C * (x_next - &next.x_pred);
+        let P = &steps[k].P_est + &C * (P_next - &next.P_pred) * C.transpose();
+        smoothed[k] = (x, P);
+    }
+    smoothed
+}
+
+/// A row of the measurement vector, with the position of its sensor among the filtered ones.
+#[derive(Clone, Copy)]
+enum Measurement {
+    Range(usize),
+    Bearing(usize),
+    /// [`EkfConfig::expected_altitude`], which comes from no sensor.
+    Altitude,
+}
+
+impl Measurement {
+    fn sensor(self) -> Option<usize> {
+        match self {
+            Measurement::Range(k) | Measurement::Bearing(k) => Some(k),
+            Measurement::Altitude => None,
         }
     }
 }
+
+/// Number of rows measured by sensors.
+fn sensor_rows(rows: &[Measurement]) -> usize {
+    rows.iter().filter(|m| m.sensor().is_some()).count()
+}
+
+/// Inverse of a covariance matrix, or `None` if it is singular.
+///
+/// Single precision goes through an LU decomposition, as the closed-form inverse nalgebra uses
+/// for small matrices cancels catastrophically when a large prior covariance dominates.
+fn invert(matrix: DMatrix<Real>) -> Option<DMatrix<Real>> {
+    if cfg!(feature = "f32") {
+        matrix.lu().try_inverse()
+    } else {
+        matrix.try_inverse()
+    }
+}
+
+/// `angle` in radians wrapped into `[-pi, pi)`.
+fn wrap_angle(angle: Real) -> Real {
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+/// Predicted range from a sensor at `s` to a target at the first `dims` entries of `x`, both in
+/// the axes of the state, at least `epsilon`.
+fn range(x: &DVector<Real>, dims: usize, s: &[Real; 3], epsilon: Real) -> Real {
+    (0..dims)
+        .map(|i| (x[i] - s[i]).powi(2))
+        .sum::<Real>()
+        .sqrt()
+        .max(epsilon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(east: Real, north: Real, dist: Real) -> Sensor {
+        Sensor {
+            enu: Enu {
+                east: Meters::new(east as f64),
+                north: Meters::new(north as f64),
+                up: Meters::new(0.0),
+            },
+            dist,
+            stddev: None,
+            max_dist: None,
+            bearing: None,
+        }
+    }
+
+    /// Sensors around the origin with their exact ranges to `target`.
+    fn sensors_around(target: (Real, Real)) -> Vec<Sensor> {
+        [(-150.0, -40.0), (0.0, 180.0), (70.0, -220.0), (200.0, 30.0)]
+            .into_iter()
+            .map(|(east, north)| {
+                let dist = (target.0 - east).hypot(target.1 - north);
+                sensor(east, north, dist)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn covariance_stays_symmetric_and_positive_semi_definite() {
+        let mut ekf = Ekf::with_config(0.0, 0.0, EkfConfig::default());
+        let sensors = sensors_around((30.0, 40.0));
+        for _ in 0..200 {
+            let (x_pred, P_pred) = ekf.predict(0.05);
+            ekf.update(x_pred, P_pred, &sensors);
+            let P = &ekf.P_est;
+            let asymmetry = (P - P.transpose()).amax();
+            assert!(asymmetry <= STRICT_SYMMETRY_TOL * P.amax(), "asymmetry {asymmetry:e}");
+            let min = P.clone().symmetric_eigen().eigenvalues.min();
+            assert!(min >= -STRICT_SYMMETRY_TOL * P.amax(), "eigenvalue {min:e}");
+        }
+    }
+
+    #[test]
+    fn collinear_sensors_give_a_finite_update() {
+        // exact ranges along the line make the innovation covariance exactly singular
+        let mut ekf = Ekf::with_config(50.0, 0.0, EkfConfig::default());
+        let sensors: Vec<Sensor> = [(0.0, 50.0), (100.0, 50.0), (200.0, 150.0)]
+            .into_iter()
+            .map(|(east, dist)| Sensor {
+                stddev: Some(0.0),
+                ..sensor(east, 0.0, dist)
+            })
+            .collect();
+        let (x_pred, P_pred) = ekf.predict(0.05);
+        ekf.update(x_pred, P_pred, &sensors);
+        assert!(ekf.last_update.H.is_some());
+        assert!(ekf.x_est.iter().all(|v| v.is_finite()), "x_est = {}", ekf.x_est);
+        assert!(ekf.P_est.iter().all(|v| v.is_finite()), "P_est = {}", ekf.P_est);
+    }
+
+    #[test]
+    fn clamps_the_speed_after_a_jump() {
+        let config = EkfConfig {
+            max_speed: Some(20.0),
+            measurement_stddev: 1.0,
+            ..Default::default()
+        };
+        let mut ekf = Ekf::with_config(0.0, 0.0, config);
+        let still = sensors_around((30.0, 40.0));
+        for _ in 0..5 {
+            let (x_pred, P_pred) = ekf.predict(0.05);
+            ekf.update(x_pred, P_pred, &still);
+        }
+        // a kilometer away within a single frame
+        let jumped = sensors_around((1030.0, 40.0));
+        let (x_pred, P_pred) = ekf.predict(0.05);
+        ekf.update(x_pred, P_pred, &jumped);
+        let (east, north) = ekf.velocity();
+        let speed = east.hypot(north);
+        assert!(speed <= 20.0 * (1.0 + Real::EPSILON), "speed {speed} m/s");
+        assert!(speed > 19.0, "speed {speed} m/s");
+    }
+
+    #[test]
+    fn hdop_of_a_right_triangle() {
+        // line-of-sight rows (1, 1), (-1, 1) and (1, -1) over sqrt(2) give H^T H = [1.5 -0.5;
+        // -0.5 1.5], whose inverse has trace 1.5
+        let target = (50.0, 50.0);
+        let sensors = [
+            sensor(0.0, 0.0, 0.0),
+            sensor(100.0, 0.0, 0.0),
+            sensor(0.0, 100.0, 0.0),
+        ];
+        let H = DMatrix::from_fn(3, 2, |i, j| {
+            let p = sensors[i].position();
+            let dist = (target.0 - p[0]).hypot(target.1 - p[1]);
+            ([target.0, target.1][j] - p[j]) / dist
+        });
+        let hdop = horizontal_dop(&H).unwrap();
+        assert!((hdop - 1.5f64.sqrt() as Real).abs() < 1e-6, "HDOP {hdop}");
+    }
+
+    #[test]
+    fn trilaterates_a_known_position() {
+        use geoconv::{CoordinateSystem, Degrees, Lle, Wgs84};
+
+        // the modules of the old direct-solve test in main.rs
+        let lles = [
+            (52.5767238253396, 16.768104883333333),
+            (52.57671242178453, 16.76808677936311),
+            (52.57670083223408, 16.7680328733773),
+            (52.576835055555556, 16.768655718723892),
+        ]
+        .map(|(lat, lon)| {
+            Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0))
+        });
+        let target: (Real, Real) = (120.0, 90.0);
+        let sensors: Vec<Sensor> = lles
+            .iter()
+            .map(|lle| {
+                let enu = CoordinateSystem::lle_to_enu(&lles[0], lle);
+                let (east, north) = (enu.east.as_float() as Real, enu.north.as_float() as Real);
+                sensor(east, north, (target.0 - east).hypot(target.1 - north))
+            })
+            .collect();
+        let (x, y) = trilaterate(&sensors).unwrap();
+        let error = (x - target.0).hypot(y - target.1);
+        assert!(error < 0.1, "({x}, {y}) is {error} m from the target");
+        assert_eq!(trilaterate(&sensors[..2]), None);
+    }
+
+    #[test]
+    fn range_is_the_same_in_either_convention() {
+        use geoconv::{CoordinateSystem, Degrees, Lle, Wgs84};
+
+        let lle =
+            |lat, lon| Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0));
+        let reference = lle(52.5775, 16.7705);
+        let module = lle(52.5767, 16.7681);
+        let target = (120.0, -80.0);
+        let expected = {
+            let enu = CoordinateSystem::lle_to_enu(&reference, &module);
+            let (east, north) = (enu.east.as_float() as Real, enu.north.as_float() as Real);
+            (target.0 - east).hypot(target.1 - north)
+        };
+
+        // sensors placed relative to the reference, the filter convention
+        let ekf = Ekf::with_config(target.0, target.1, EkfConfig::default());
+        let sensor = Sensor {
+            enu: CoordinateSystem::lle_to_enu(&reference, &module),
+            ..sensor(0.0, 0.0, 0.0)
+        };
+        let range = ekf.range_to(&sensor);
+        assert!((range - expected).abs() < 1e-3, "{range} m against {expected} m");
+
+        // the reference relative to the sensor, negated
+        let inverse = CoordinateSystem::lle_to_enu(&module, &reference);
+        let (east, north) = (-inverse.east.as_float() as Real, -inverse.north.as_float() as Real);
+        let range = (target.0 - east).hypot(target.1 - north);
+        assert!((range - expected).abs() < 0.01, "{range} m against {expected} m");
+    }
+
+    #[test]
+    fn ned_filter_matches_enu_filter() {
+        let target = (120.0, -80.0);
+        let filter = |convention| {
+            let config = EkfConfig {
+                convention,
+                seed: false,
+                ..Default::default()
+            };
+            Ekf::with_config(target.0, target.1, config)
+        };
+        let (mut enu, mut ned) = (filter(Convention::Enu), filter(Convention::Ned));
+        assert_eq!(ned.x_est.rows(0, 2).as_slice(), [target.1, target.0]);
+
+        let known = sensor(-150.0, 60.0, 0.0);
+        let expected = (target.0 + 150.0).hypot(target.1 - 60.0);
+        for ekf in [&enu, &ned] {
+            let range = ekf.range_to(&known);
+            assert!((range - expected).abs() < 1e-9, "{range} m against {expected} m");
+        }
+
+        // off the target, so that the innovations are not all 0
+        let sensors = sensors_around((100.0, -60.0));
+        for ekf in [&mut enu, &mut ned] {
+            let (x_pred, P_pred) = ekf.predict(0.05);
+            ekf.update(x_pred, P_pred, &sensors);
+        }
+        let (H_enu, H_ned) =
+            (enu.last_update.H.clone().unwrap(), ned.last_update.H.clone().unwrap());
+        assert_eq!(H_enu.nrows(), sensors.len());
+        assert_eq!(H_ned.column(0), H_enu.column(1));
+        assert_eq!(H_ned.column(1), H_enu.column(0));
+        assert_eq!(enu.last_update.innovation, ned.last_update.innovation);
+        let ((east, north), (ned_east, ned_north)) = (enu.east_north(), ned.east_north());
+        assert!((east - ned_east).abs() < 1e-9 && (north - ned_north).abs() < 1e-9);
+        assert_eq!(enu.velocity(), ned.velocity());
+    }
+
+    #[test]
+    fn maps_innovation_rows_to_sensors() {
+        let mut ekf = Ekf::with_config(30.0, 40.0, EkfConfig::default());
+        let mut sensors = sensors_around((30.0, 40.0));
+        // a bearing alone, listed after the ranges
+        sensors[2] = Sensor {
+            dist: 0.0,
+            bearing: Some(0.0),
+            ..sensors[2]
+        };
+        let (x_pred, P_pred) = ekf.predict(0.05);
+        ekf.update(x_pred, P_pred, &sensors);
+        assert_eq!(ekf.last_update.innovation.as_ref().unwrap().len(), 4);
+        assert_eq!(ekf.last_update.rows, [Some(0), Some(1), Some(3), Some(2)]);
+        assert_eq!(ekf.last_update.bearings, [2]);
+    }
+
+    #[test]
+    fn merges_coincident_sensors() {
+        let sensors = vec![
+            sensor(0.0, 0.0, 100.0),
+            sensor(200.0, 0.0, 150.0),
+            sensor(0.5, 0.0, 104.0),
+            sensor(0.0, 200.0, 120.0),
+        ];
+        let (indices, merged_sensors, merged) = merge_coincident(vec![3, 5, 6, 8], sensors, 1.0);
+        assert_eq!(indices, [3, 5, 8]);
+        assert_eq!(merged, [6]);
+        let first = merged_sensors[0];
+        assert_eq!(first.position(), [0.25, 0.0, 0.0]);
+        assert_eq!(first.dist, 102.0);
+        assert_eq!(merged_sensors[1].dist, 150.0);
+        assert_eq!(merged_sensors[2].dist, 120.0);
+    }
+}
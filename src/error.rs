@@ -0,0 +1,82 @@
+use std::{fmt, path::PathBuf};
+
+/// Errors that abort a simulation run.
+#[derive(Debug)]
+pub enum SimError {
+    Io(std::io::Error),
+    Csv(csv::Error),
+    WebSocket(Box<tungstenite::Error>),
+    /// A file in the input directory whose name does not end with a module number.
+    NoFileNumber(PathBuf),
+    /// Every module in the modules CSV was rejected.
+    NoModules,
+    /// A module id given on the command line that is not among the modules.
+    UnknownModule(i32),
+    /// A `--start-frame` or `--end-frame` past the last row of the input.
+    FrameOutOfRange {
+        frame: usize,
+        frames: usize,
+    },
+    /// An `--end-frame` not after the `--start-frame`.
+    EmptyFrameRange {
+        start: usize,
+        end: usize,
+    },
+    /// Different numbers of modules and distance files.
+    CountMismatch {
+        modules: usize,
+        files: usize,
+    },
+    /// Recordings of a `--batch` manifest failed, their errors are logged separately.
+    BatchFailed {
+        failed: usize,
+        total: usize,
+    },
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::Io(err) => write!(f, "{err}"),
+            SimError::Csv(err) => write!(f, "{err}"),
+            SimError::WebSocket(err) => write!(f, "{err}"),
+            SimError::NoFileNumber(path) => {
+                write!(f, "{} is not named like dist_<module>.csv[.gz]", path.display())
+            }
+            SimError::NoModules => write!(f, "no module has a valid position"),
+            SimError::UnknownModule(id) => write!(f, "module {id} is not in the modules CSV"),
+            SimError::FrameOutOfRange { frame, frames } => {
+                write!(f, "frame {frame} is out of range, the input has {frames} rows")
+            }
+            SimError::EmptyFrameRange { start, end } => {
+                write!(f, "end frame {end} is not after start frame {start}")
+            }
+            SimError::CountMismatch { modules, files } => {
+                write!(f, "{modules} modules but {files} distance files")
+            }
+            SimError::BatchFailed { failed, total } => {
+                write!(f, "{failed} of {total} recordings failed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+impl From<std::io::Error> for SimError {
+    fn from(err: std::io::Error) -> Self {
+        SimError::Io(err)
+    }
+}
+
+impl From<csv::Error> for SimError {
+    fn from(err: csv::Error) -> Self {
+        SimError::Csv(err)
+    }
+}
+
+impl From<tungstenite::Error> for SimError {
+    fn from(err: tungstenite::Error) -> Self {
+        SimError::WebSocket(Box::new(err))
+    }
+}
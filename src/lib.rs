@@ -0,0 +1,29 @@
+//! Drone localization from module range reports: the extended Kalman filter, the offline
+//! simulation and the real-time processor.
+#![allow(non_snake_case)]
+// conversions between `ekf::Real` and `f64` only change the type with the `f32` feature
+#![cfg_attr(not(feature = "f32"), allow(clippy::unnecessary_cast))]
+
+pub mod aggregate;
+mod checkpoint;
+pub mod ekf;
+pub mod error;
+mod metrics;
+mod nmea;
+mod outliers;
+pub mod processor;
+#[cfg(feature = "protobuf")]
+mod proto;
+mod record;
+mod roster;
+pub mod sim;
+mod smoothing;
+pub mod synth;
+mod tls;
+pub mod units;
+mod utm;
+
+pub use aggregate::Module;
+pub use ekf::{Convention, Ekf, Sensor};
+pub use processor::{Processor, run};
+pub use sim::{Estimate, FixType, LocationSimArgs, for_each_estimate, simulate, simulate_i2s};
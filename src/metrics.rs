@@ -0,0 +1,193 @@
+//! Operational counters of the processor, served in the Prometheus text format, and a health
+//! check for orchestration.
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::aggregate::{Module, RETAIN_PERIOD};
+
+/// Longest wait for a client to send its request or accept the response, so that a client that
+/// connects and stalls does not hold up the requests after it.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Estimates computed, one per track and frame.
+    pub fixes: AtomicU64,
+    /// Frames in which no module reported a drone.
+    pub no_detection: AtomicU64,
+    /// Frames with a detection but fewer than 3 modules.
+    pub not_enough: AtomicU64,
+    /// Ranges rejected by the gates of the filter.
+    pub gated: AtomicU64,
+    /// Module reports that could not be parsed.
+    pub malformed: AtomicU64,
+    /// Modules retained in the latest frame.
+    pub modules: AtomicU64,
+    /// Frames whose computation took longer than the read period.
+    pub overruns: AtomicU64,
+    /// Open module WebSocket connections.
+    pub connections: AtomicU64,
+    /// When the latest estimate was computed.
+    pub last_fix: Mutex<Option<Instant>>,
+}
+
+/// Body of the `/health` response.
+#[derive(Serialize)]
+struct Health {
+    /// Modules that reported within the retain period.
+    fresh_modules: usize,
+    /// Whether any fresh module reports a drone.
+    detecting: bool,
+    /// Seconds since the latest estimate, `null` before the first one.
+    last_fix_age_secs: Option<f64>,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("fixes_total", "counter", "Estimates computed", &self.fixes),
+            (
+                "no_detection_frames_total",
+                "counter",
+                "Frames without a detection",
+                &self.no_detection,
+            ),
+            (
+                "not_enough_frames_total",
+                "counter",
+                "Frames with fewer than 3 modules",
+                &self.not_enough,
+            ),
+            ("gated_ranges_total", "counter", "Ranges rejected by the filter gates", &self.gated),
+            (
+                "malformed_reports_total",
+                "counter",
+                "Module reports that could not be parsed",
+                &self.malformed,
+            ),
+            ("modules", "gauge", "Modules retained in the latest frame", &self.modules),
+            (
+                "overrun_frames_total",
+                "counter",
+                "Frames computed slower than the read period",
+                &self.overruns,
+            ),
+            ("connections", "gauge", "Open module WebSocket connections", &self.connections),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let name = format!("drone_processor_{name}");
+            text.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+            text.push_str(&format!("{name} {}\n", value.load(Ordering::Relaxed)));
+        }
+        text
+    }
+}
+
+/// Serves `GET /metrics` and `GET /health` on `addr` from a background thread and returns the
+/// address it listens on.
+///
+/// `/health` answers 503 Service Unavailable while no module has reported within the retain
+/// period, so that a load balancer can route away from a processor that receives no data.
+pub fn serve(
+    addr: &str,
+    metrics: Arc<Metrics>,
+    modules: Arc<Mutex<HashMap<String, Module>>>,
+) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    log::info!("Serving metrics on http://{addr}/metrics");
+    spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics, &modules));
+            if let Err(err) = result {
+                log::warn!("Error serving metrics: {err}");
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+fn health(metrics: &Metrics, modules: &Mutex<HashMap<String, Module>>) -> Health {
+    let now = Instant::now();
+    let (fresh_modules, detecting) = modules
+        .lock()
+        .values()
+        .filter(|m| now.saturating_duration_since(m.updated) < RETAIN_PERIOD)
+        .fold((0, false), |(n, detecting), m| (n + 1, detecting || m.drone));
+    Health {
+        fresh_modules,
+        detecting,
+        last_fix_age_secs: metrics
+            .last_fix
+            .lock()
+            .map(|fix| now.saturating_duration_since(fix).as_secs_f64()),
+    }
+}
+
+fn respond(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    modules: &Mutex<HashMap<String, Module>>,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        Some("/health") => {
+            let health = health(metrics, modules);
+            let status = if health.fresh_modules > 0 {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = serde_json::to_string(&health).map_err(std::io::Error::other)?;
+            (status, "application/json", body)
+        }
+        _ => ("404 Not Found", "text/plain", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn stalled_client_does_not_block_health() {
+        let modules = Arc::new(Mutex::new(HashMap::new()));
+        let addr = serve("127.0.0.1:0", Arc::new(Metrics::default()), modules).unwrap();
+        // connects and never sends its request
+        let _stalled = TcpStream::connect(addr).unwrap();
+
+        let start = Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(4 * CLIENT_TIMEOUT)).unwrap();
+        client.write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        assert!(start.elapsed() < 2 * CLIENT_TIMEOUT, "answered after {:?}", start.elapsed());
+    }
+}
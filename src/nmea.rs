@@ -0,0 +1,94 @@
+//! NMEA 0183 encoding of track estimates for tools that ingest a GPS receiver's output.
+use geoconv::{Lle, Wgs84};
+
+use crate::ekf::Ekf;
+
+/// Fix quality of an estimate with a measurement update, "GPS fix".
+const FIX_MEASURED: u8 = 1;
+/// Fix quality of an estimate predicted without a measurement update, "estimated".
+const FIX_ESTIMATED: u8 = 6;
+
+/// Formats the estimate as a `$GPGGA` sentence, including the checksum but not the line ending.
+///
+/// `timestamp` is in seconds since the UNIX epoch and only its UTC time of day is encoded. The
+/// number of modules used in the last update stands in for the satellite count.
+pub fn gga(timestamp: f64, lle: &Lle<Wgs84>, ekf: &Ekf) -> String {
+    let measured = ekf.last_update.H.is_some();
+    let (quality, modules) = if measured {
+        (FIX_MEASURED, ekf.last_update.used.len())
+    } else {
+        (FIX_ESTIMATED, 0)
+    };
+    let hdop = ekf
+        .hdop()
+        .map_or(String::new(), |hdop| format!("{hdop:.1}"));
+
+    let centis = (timestamp.rem_euclid(86_400.0) * 100.0).round() as u64 % 8_640_000;
+    let (secs, centis) = (centis / 100, centis % 100);
+    let time = format!("{:02}{:02}{:02}.{centis:02}", secs / 3600, secs / 60 % 60, secs % 60);
+
+    let lat = lle.latitude.as_float();
+    let lon = lle.longitude.as_float();
+    let body = format!(
+        "GPGGA,{time},{},{},{},{},{quality},{modules:02},{hdop},{:.1},M,,M,,",
+        degrees_minutes(lat, 2),
+        if lat < 0.0 { 'S' } else { 'N' },
+        degrees_minutes(lon, 3),
+        if lon < 0.0 { 'W' } else { 'E' },
+        lle.elevation.as_float(),
+    );
+    format!("${body}*{:02X}", checksum(&body))
+}
+
+/// Formats the absolute value of `degrees` as `ddmm.mmmm`, with the degrees padded to `width`.
+fn degrees_minutes(degrees: f64, width: usize) -> String {
+    // round in units of 1e-4 minutes first so that 59.99999 minutes carry into the degrees
+    let units = (degrees.abs() * 60.0 * 10_000.0).round() as u64;
+    let (degrees, units) = (units / 600_000, units % 600_000);
+    format!("{degrees:0width$}{:02}.{:04}", units / 10_000, units % 10_000)
+}
+
+/// XOR of the bytes between `$` and `*`.
+fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+#[cfg(test)]
+mod tests {
+    use geoconv::{Degrees, Meters};
+
+    use super::*;
+
+    fn lle(lat: f64, lon: f64, alt: f64) -> Lle<Wgs84> {
+        Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(alt))
+    }
+
+    #[test]
+    fn checksum_of_a_known_sentence() {
+        let body = "GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,";
+        assert_eq!(checksum(body), 0x47);
+    }
+
+    #[test]
+    fn encodes_a_northern_eastern_fix() {
+        let ekf = Ekf::new(0.0, 0.0, None);
+        let sentence = gga(45_319.0, &lle(48.1173, 11.0 + 31.0 / 60.0, 545.4), &ekf);
+        assert_eq!(sentence, "$GPGGA,123519.00,4807.0380,N,01131.0000,E,6,00,,545.4,M,,M,,*54");
+    }
+
+    #[test]
+    fn encodes_a_southern_western_fix() {
+        let ekf = Ekf::new(0.0, 0.0, None);
+        // the time of day of a timestamp long after the epoch
+        let timestamp = 19_000.0 * 86_400.0 + 86_399.99;
+        let sentence = gga(timestamp, &lle(-33.4489, -70.6693, -12.0), &ekf);
+        assert_eq!(sentence, "$GPGGA,235959.99,3326.9340,S,07040.1580,W,6,00,,-12.0,M,,M,,*4E");
+    }
+
+    #[test]
+    fn minutes_carry_into_the_degrees() {
+        assert_eq!(degrees_minutes(52.0 + 59.99999 / 60.0, 2), "5300.0000");
+        assert_eq!(degrees_minutes(-(16.0 + 59.99999 / 60.0), 3), "01700.0000");
+        assert_eq!(degrees_minutes(52.0 + 59.9999 / 60.0, 2), "5259.9999");
+    }
+}
@@ -0,0 +1,52 @@
+/// Median of `values`, or `None` if there are none.
+pub fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+
+    let n_sorted = sorted.len();
+    let median = if n_sorted.is_multiple_of(2) {
+        let n_half = n_sorted / 2;
+        (sorted[n_half - 1] + sorted[n_half]) / 2.0
+    } else {
+        sorted[n_sorted / 2]
+    };
+    Some(median)
+}
+
+/// Indices of the distances further than `band * median` from the median distance.
+///
+/// Nothing is rejected when fewer than 3 distances are given.
+pub fn median_outliers(dists: &[f64], band: f64) -> Vec<usize> {
+    if dists.len() < 3 {
+        return Vec::new();
+    }
+    let median = median(dists).unwrap();
+    dists
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| (median - *d).abs() >= median * band)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_of_odd_and_even_lengths() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[4.0]), Some(4.0));
+        assert_eq!(median(&[9.0, 1.0, 5.0]), Some(5.0));
+        assert_eq!(median(&[9.0, 1.0, 5.0, 3.0]), Some(4.0));
+    }
+
+    #[test]
+    fn rejects_nothing_from_fewer_than_3_distances() {
+        assert!(median_outliers(&[100.0, 1000.0], 0.25).is_empty());
+        assert_eq!(median_outliers(&[100.0, 110.0, 1000.0], 0.25), vec![2]);
+    }
+}
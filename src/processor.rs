@@ -1,31 +1,312 @@
 #![allow(non_snake_case)]
 use std::{
-    collections::HashMap,
-    net::TcpListener,
-    sync::Arc,
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
     thread::{sleep, spawn},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use geoconv::{CoordinateSystem, Degrees, Enu, Lle, Meters, Wgs84};
 use parking_lot::Mutex;
+#[cfg(feature = "protobuf")]
+use prost::Message;
+use serde::Deserialize;
 use tungstenite::{accept, connect};
 
-use crate::ekf::{Ekf, Sensor};
-
-#[derive(Clone, Copy)]
-pub struct Module {
-    // pub mac: String,
-    // pub ip: String,
-    pub lat: f64,
-    pub lon: f64,
-    pub alt: f64,
-    pub drone: bool,
-    pub dist: f64,
-    pub updated: Instant,
+use crate::aggregate::{self, Aggregator, Frame, Module};
+use crate::checkpoint::Checkpoint;
+use crate::ekf::{self, Ekf, EkfConfig, RangeModel, Real, Sensor};
+use crate::metrics::{self, Metrics};
+use crate::nmea;
+use crate::outliers;
+#[cfg(feature = "protobuf")]
+use crate::proto::TrackPoint;
+use crate::record::Recorder;
+use crate::roster::{self, Roster};
+use crate::smoothing::{self, MovingAverage};
+use crate::tls;
+use crate::units::Units;
+
+const MIN_CALIBRATED_STDDEV: f64 = 0.1;
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+/// How often the filter state is written to the checkpoint file.
+const CHECKPOINT_PERIOD: Duration = Duration::from_secs(1);
+/// How often the layout of the reporting modules is checked for collinearity.
+const LAYOUT_CHECK_PERIOD: Duration = Duration::from_secs(60);
+/// Consecutive frames computed slower than the read period between overrun warnings.
+const OVERRUN_WARN_FRAMES: u32 = 20;
+/// How often the accept loop checks for a shutdown request.
+const ACCEPT_POLL_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(clap::Args)]
+pub struct ProcessorConfig {
+    /// Address the module WebSocket server listens on
+    #[arg(long)]
+    pub ws_in: String,
+    /// Address of the WebSocket estimates are sent to, `ws://` unless given as `wss://host:port`
+    #[arg(long)]
+    pub ws_out: String,
+    /// PEM certificate chain served to modules, enabling TLS on --ws-in
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Close a module connection that sends nothing for this many seconds
+    #[arg(long, default_value_t = 30.0, value_parser = crate::sim::parse_positive)]
+    pub read_timeout_secs: f64,
+    /// Refuse module connections while this many are open
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connections: u64,
+    /// Nominal drone altitude in meters, used to convert slant ranges into horizontal ranges
+    /// (or as the initial altitude in 3D mode)
+    #[arg(long)]
+    pub drone_alt: Option<Real>,
+    /// Latitude of the ENU reference, the centroid of the modules by default
+    #[arg(long, requires = "ref_lon")]
+    pub ref_lat: Option<f64>,
+    /// Longitude of the ENU reference
+    #[arg(long, requires = "ref_lat")]
+    pub ref_lon: Option<f64>,
+    /// East coordinate in meters from the ENU reference the filter starts at, and is reset to
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub init_east: Real,
+    /// North coordinate in meters from the ENU reference the filter starts at
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub init_north: Real,
+    /// Drop up to this many sensors to recover from a singular innovation covariance
+    #[arg(long, default_value_t = 0)]
+    pub singular_retries: usize,
+    /// Check the filter invariants after every update and log violations
+    #[arg(long)]
+    pub strict: bool,
+    /// Emit estimates when the module with this MAC reports instead of on every solve
+    #[arg(long)]
+    pub leader: Option<String>,
+    /// Reject ranges further than this many meters from the predicted range
+    #[arg(long)]
+    pub gate_meters: Option<Real>,
+    /// Learn each module's measurement noise from its readings during the first seconds
+    #[arg(long)]
+    pub calibrate_secs: Option<f64>,
+    /// Suppress output while the trace of the position covariance exceeds this value (m^2)
+    #[arg(long)]
+    pub converged_trace: Option<Real>,
+    /// Estimate altitude with a 3D filter, seeded from --drone-alt
+    #[arg(long)]
+    pub three_d: bool,
+    /// Fit the altitude to the slant ranges after each 2D update when the modules are at
+    /// different altitudes
+    #[arg(long, conflicts_with = "three_d")]
+    pub fit_altitude: bool,
+    /// Altitude in meters the drone is expected to fly at, added to every 3D update as a
+    /// measurement of the estimated altitude
+    #[arg(long, requires = "three_d")]
+    pub expected_altitude: Option<Real>,
+    /// Standard deviation of --expected-altitude in meters
+    #[arg(long, default_value_t = ekf::EXPECTED_ALTITUDE_STDDEV, requires = "expected_altitude")]
+    pub expected_altitude_stddev: Real,
+    /// Reinitialize the filter when no solution was computed for this many seconds
+    #[arg(long)]
+    pub reset_after_secs: Option<f64>,
+    /// Append the estimated speed (m/s) and bearing (degrees from north) to text messages
+    #[arg(long)]
+    pub velocity: bool,
+    /// Append the horizontal dilution of precision of the sensor geometry to text messages,
+    /// empty when no measurement update was applied
+    #[arg(long)]
+    pub hdop: bool,
+    /// Track several drones, routing each range by the track id in the report or, without one,
+    /// to the track with the closest predicted range
+    #[arg(long)]
+    pub multi_target: bool,
+    /// Drop modules whose distance is further than this fraction of the median distance (e.g. 0.25)
+    #[arg(long)]
+    pub outlier_factor: Option<f64>,
+    /// Weight of each new distance in a per-module exponential moving average applied as the
+    /// reports arrive, e.g. 0.3 to damp spikes, 1 to use the distances as reported. The average
+    /// restarts when a module stops reporting the drone or its reports expire
+    #[arg(long, default_value_t = 1.0, value_parser = crate::smoothing::parse_alpha)]
+    pub range_smoothing: f64,
+    /// Append `,stale,age_ms` to text messages and keep re-sending the last fix without a fresh
+    /// solution, marking it stale once it is older than this many milliseconds
+    #[arg(long)]
+    pub stale_after_ms: Option<u128>,
+    /// Send the latest fix of each track at most this many times a second (e.g. 5) for clients
+    /// that cannot keep up, while the filter still updates on every frame
+    #[arg(long, value_parser = crate::sim::parse_positive)]
+    pub limit_rate: Option<f64>,
+    /// Merge modules closer than this many meters to each other into one, averaging their ranges
+    #[arg(long)]
+    pub merge_distance: Option<Real>,
+    /// Form of the range measurement equation, squared ranges stay well-behaved with the drone
+    /// over a module
+    #[arg(long, value_enum, default_value = "range")]
+    pub range_model: RangeModel,
+    /// Smallest predicted range in meters, keeping the range Jacobian finite with the estimate
+    /// on a module; raise it (e.g. to 1) if the filter jumps as the drone flies over a module
+    #[arg(long, default_value_t = ekf::RANGE_EPSILON)]
+    pub range_epsilon: Real,
+    /// Limit the estimated horizontal speed to this many m/s
+    #[arg(long)]
+    pub max_speed: Option<Real>,
+    /// Update from the intersection of two ranges nearest to the prediction when only two
+    /// modules report, with the range noise inflated by this factor (e.g. 3)
+    #[arg(long)]
+    pub two_sensor_factor: Option<Real>,
+    /// Raise the process noise by up to this factor while the innovations are larger than
+    /// expected, e.g. 100 to follow maneuvers
+    #[arg(long)]
+    pub max_process_noise_scale: Option<Real>,
+    /// Send the mean of the last N estimated positions of each track, 1 for no averaging
+    #[arg(long, default_value_t = 1, value_parser = crate::smoothing::parse_window)]
+    pub smooth_window: usize,
+    /// When computing a frame overruns the 50 ms read period, skip the frames it ran into and
+    /// wait for the next period instead of starting the next frame at once
+    #[arg(long)]
+    pub skip_late_frames: bool,
+    /// Periodically save the filter state to this JSON file and resume from it on startup
+    #[arg(long)]
+    pub checkpoint: Option<PathBuf>,
+    /// Ignore a checkpoint saved more than this many seconds ago
+    #[arg(long, default_value_t = 10.0, requires = "checkpoint")]
+    pub checkpoint_max_age_secs: f64,
+    /// Take module positions from the JSON object served at this http:// URL, mapping each MAC
+    /// to its `lat`, `lon` and optional `alt`, instead of from the reports, which may then leave
+    /// them empty. Reports from MACs it does not list are ignored
+    #[arg(long)]
+    pub roster_url: Option<String>,
+    /// Fetch the module roster again every this many seconds
+    #[arg(
+        long,
+        default_value_t = 60.0,
+        requires = "roster_url",
+        value_parser = crate::sim::parse_positive
+    )]
+    pub roster_refresh_secs: f64,
+    /// Append every valid module report to this CSV as `time,mac,lat,lon,alt,drone,dist,track`,
+    /// which `location-sim --replay` reads
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// Start a new recording file once the current one reaches this many megabytes
+    #[arg(long, default_value_t = 100, requires = "record")]
+    pub record_max_mb: u64,
+    /// Start a new recording file once the current one is this many seconds old
+    #[arg(long, requires = "record")]
+    pub record_max_secs: Option<u64>,
+    /// Serve Prometheus metrics at http://<address>/metrics and a health check reporting module
+    /// freshness at /health, e.g. 0.0.0.0:9100
+    #[arg(long)]
+    pub metrics_addr: Option<String>,
+    /// Send estimates as NMEA 0183 `$GPGGA` sentences instead of the `lon,lat,...` text
+    #[arg(long, conflicts_with_all = ["velocity", "hdop", "multi_target", "stale_after_ms"])]
+    pub nmea: bool,
+    /// Unit of the standard deviations and speed in text messages
+    #[arg(long, value_enum, default_value = "meters", conflicts_with = "nmea")]
+    pub units: Units,
+    /// Send estimates as protobuf `TrackPoint` binary messages instead of text
+    #[cfg(feature = "protobuf")]
+    #[arg(long, conflicts_with = "nmea")]
+    pub protobuf: bool,
 }
 
-pub fn run(ws_in: &str, ws_out: String) {
+/// Processor whose module WebSocket server is bound, started with [`Processor::run`].
+pub struct Processor {
+    config: ProcessorConfig,
+    server: TcpListener,
+}
+
+impl Processor {
+    /// Binds the module WebSocket server to `config.ws_in`, which may use port 0 to let the OS
+    /// pick a free port, see [`Processor::local_addr`].
+    pub fn bind(config: ProcessorConfig) -> io::Result<Self> {
+        let server = TcpListener::bind(&config.ws_in)?;
+        // non-blocking so the accept loop can notice a shutdown request between connections
+        server.set_nonblocking(true)?;
+        Ok(Self { config, server })
+    }
+
+    /// Address the module WebSocket server is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// Connects to `ws_out` and processes module reports until a shutdown signal.
+    pub fn run(self) {
+        serve(self.config, self.server);
+    }
+}
+
+/// Binds the processor to `config.ws_in` and runs it until a shutdown signal.
+pub fn run(config: ProcessorConfig) {
+    let ws_in = config.ws_in.clone();
+    match Processor::bind(config) {
+        Ok(processor) => processor.run(),
+        Err(err) => log::error!("Failed to listen on {ws_in}: {err}"),
+    }
+}
+
+fn serve(config: ProcessorConfig, server: TcpListener) {
+    let ProcessorConfig {
+        ws_in: _,
+        ws_out,
+        tls_cert,
+        tls_key,
+        read_timeout_secs,
+        max_connections,
+        drone_alt,
+        ref_lat,
+        ref_lon,
+        init_east,
+        init_north,
+        singular_retries,
+        strict,
+        leader,
+        gate_meters,
+        calibrate_secs,
+        converged_trace,
+        three_d,
+        fit_altitude,
+        expected_altitude,
+        expected_altitude_stddev,
+        reset_after_secs,
+        velocity,
+        hdop,
+        multi_target,
+        outlier_factor,
+        range_smoothing,
+        stale_after_ms,
+        limit_rate,
+        merge_distance,
+        max_speed,
+        range_model,
+        range_epsilon,
+        two_sensor_factor,
+        max_process_noise_scale,
+        smooth_window,
+        skip_late_frames,
+        checkpoint,
+        checkpoint_max_age_secs,
+        roster_url,
+        roster_refresh_secs,
+        record,
+        record_max_mb,
+        record_max_secs,
+        metrics_addr,
+        nmea,
+        units,
+        #[cfg(feature = "protobuf")]
+        protobuf,
+    } = config;
+
     // env_logger::builder()
     //     .filter_level(log::LevelFilter::Info)
     //     .target(env_logger::Target::Stdout)
@@ -39,21 +320,163 @@ pub fn run(ws_in: &str, ws_out: String) {
     //     .start()
     //     .unwrap();
 
+    if range_model == RangeModel::Tdoa {
+        // frames mix modules as they report, there is no reference module to difference against
+        log::error!("--range-model tdoa is only supported by location-sim");
+        return;
+    }
+
+    let tls_config = match tls_cert.as_deref().zip(tls_key.as_deref()) {
+        Some((cert, key)) => match tls::server_config(cert, key) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                log::error!("Failed to load the TLS certificate: {err}");
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let recorder = match record {
+        Some(path) => {
+            let max_age = record_max_secs.map(Duration::from_secs);
+            match Recorder::create(path.clone(), record_max_mb * 1_000_000, max_age) {
+                Ok(recorder) => Some(Arc::new(Mutex::new(recorder))),
+                Err(err) => {
+                    log::error!("Failed to open the recording {}: {err}", path.display());
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+
+    let roster = match &roster_url {
+        Some(url) => match roster::fetch(url) {
+            Ok(positions) => {
+                log::info!("Fetched {} module positions from {url}", positions.len());
+                let roster = Arc::new(Mutex::new(Roster::new(positions)));
+                let period = Duration::from_secs_f64(roster_refresh_secs);
+                roster::refresh(url.clone(), period, roster.clone());
+                Some(roster)
+            }
+            Err(err) => {
+                log::error!("Failed to fetch the module roster from {url}: {err}");
+                return;
+            }
+        },
+        None => None,
+    };
+
     let modules: Arc<Mutex<HashMap<String, Module>>> = Arc::new(Mutex::new(HashMap::new()));
+    let (leader_tx, leader_rx) = mpsc::channel::<()>();
+    let leader_rx = leader.is_some().then_some(leader_rx);
 
-    spawn({
+    // set on SIGINT or SIGTERM, stops the accept loop and the compute thread
+    let shutdown = Arc::new(AtomicBool::new(false));
+    if let Err(err) = ctrlc::set_handler({
+        let shutdown = shutdown.clone();
+        move || {
+            log::info!("Shutdown requested");
+            shutdown.store(true, Ordering::SeqCst);
+        }
+    }) {
+        log::warn!("Failed to install the shutdown signal handler: {err}");
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = &metrics_addr
+        && let Err(err) = metrics::serve(addr, metrics.clone(), modules.clone())
+    {
+        log::error!("Failed to serve metrics on {addr}: {err}");
+    }
+
+    let compute = spawn({
         let modules = modules.clone();
+        let shutdown = shutdown.clone();
+        let metrics = metrics.clone();
         move || {
             let read_period = Duration::from_millis(50);
-            let mut ref_lle = None;
-            let mut ekf = Ekf::new(0.0, 0.0, None);
-            loop {
+            let mut aggregator = Aggregator {
+                ref_lle: ref_lat.zip(ref_lon).map(|(lat, lon)| {
+                    Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0))
+                }),
+                two_sensor: two_sensor_factor.is_some(),
+            };
+            let new_ekf = || {
+                let config = EkfConfig {
+                    max_speed,
+                    range_model,
+                    range_epsilon,
+                    two_sensor_noise_factor: two_sensor_factor,
+                    max_process_noise_scale,
+                    merge_distance,
+                    expected_altitude,
+                    expected_altitude_stddev,
+                    ..Default::default()
+                };
+                let mut ekf = if three_d {
+                    Ekf::new_3d(init_east, init_north, drone_alt.unwrap_or(0.0), config)
+                } else {
+                    Ekf::with_config(init_east, init_north, config)
+                };
+                ekf.drone_alt = drone_alt;
+                ekf.singular_retries = singular_retries;
+                ekf.strict = strict;
+                ekf.gate_meters = gate_meters;
+                ekf
+            };
+            let mut tracks: HashMap<u32, Ekf> = HashMap::new();
+            // newest report of the last frame that updated each track
+            let mut track_updates: HashMap<u32, Instant> = HashMap::new();
+            let mut averages: HashMap<u32, MovingAverage> = HashMap::new();
+            // latest altitude of each track from --fit-altitude
+            let mut altitudes: HashMap<u32, Real> = HashMap::new();
+            let max_age = Duration::from_secs_f64(checkpoint_max_age_secs);
+            if let Some(saved) = checkpoint
+                .as_ref()
+                .and_then(|p| Checkpoint::load(p, max_age))
+            {
+                // the saved states are only meaningful relative to the saved reference
+                let saved_ref = saved.ref_lle();
+                if aggregator.ref_lle.as_ref().is_some_and(|r| {
+                    r.latitude.as_float() != saved_ref.latitude.as_float()
+                        || r.longitude.as_float() != saved_ref.longitude.as_float()
+                }) {
+                    log::warn!("Ignoring checkpoint saved with a different ENU reference");
+                } else {
+                    aggregator.ref_lle = Some(saved_ref);
+                    for state in &saved.tracks {
+                        let mut ekf = new_ekf();
+                        match state.restore(&mut ekf) {
+                            Ok(()) => {
+                                tracks.insert(state.track, ekf);
+                            }
+                            Err(err) => log::warn!("Not restoring track {}: {err}", state.track),
+                        }
+                    }
+                    log::info!("Restored {} track(s) from the checkpoint", tracks.len());
+                }
+            }
+            let mut last_checkpoint = Instant::now();
+            let mut last_layout_check: Option<Instant> = None;
+            // last emitted text fix of every track and when it was computed
+            let mut last_fixes: HashMap<u32, (String, Instant)> = HashMap::new();
+            let mut calibration =
+                calibrate_secs.map(|secs| Calibration::new(Duration::from_secs_f64(secs)));
+            let mut noise = HashMap::new();
+            let mut backoff = MIN_RECONNECT_BACKOFF;
+            let send_period = limit_rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+            while !shutdown.load(Ordering::SeqCst) {
                 // let client = reqwest::blocking::Client::new();
-                let (mut socket, _response) = match connect(format!("ws://{ws_out}")) {
+                let (mut socket, _response) = match connect(tls::client_url(&ws_out)) {
                     Ok(c) => c,
                     Err(e) => {
-                        log::error!("Website WebSocket connection error: {e}");
-                        sleep(Duration::from_millis(1000));
+                        log::error!(
+                            "Website WebSocket connection error: {e}, retrying in {backoff:?}"
+                        );
+                        sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
                         continue;
                     }
                 };
@@ -81,95 +504,221 @@ pub fn run(ws_in: &str, ws_out: String) {
                 //     }
                 // }
 
-                let mut last_predict = Instant::now();
-                loop {
+                let mut last_frame = Instant::now();
+                let mut last_solution = Instant::now();
+                let mut leader_reported = false;
+                // consecutive frames computed slower than the read period
+                let mut overruns = 0;
+                // latest message of every track waiting for the next send, see --limit-rate
+                let mut outbox: BTreeMap<u32, tungstenite::Message> = BTreeMap::new();
+                let mut next_send = Instant::now();
+                'frames: loop {
+                    if shutdown.load(Ordering::SeqCst) {
+                        if let (Some(path), Some(ref_lle)) = (&checkpoint, &aggregator.ref_lle) {
+                            save_checkpoint(path, ref_lle, &tracks);
+                        }
+                        // a close frame tells the website the stream ended on purpose
+                        if let Err(err) = socket.close(None) {
+                            log::warn!("Error closing drone WebSocket: {err}");
+                        }
+                        return;
+                    }
                     let start = Instant::now();
+                    let emit = leader_rx.is_none() || leader_reported;
 
+                    // take what the solve needs while holding the lock instead of cloning the map,
+                    // outliers are dropped until they report again
                     let mut lock = modules.lock();
-                    if ref_lle.is_none() {
-                        let m = lock.values().next();
-                        if let Some(m) = m {
-                            ref_lle = Some(Lle::<Wgs84>::new(
-                                Degrees::new(m.lat),
-                                Degrees::new(m.lon),
-                                Meters::new(0.0),
-                            ))
-                        }
+                    aggregator.retain(&mut lock, Instant::now());
+                    if let Some(factor) = outlier_factor {
+                        reject_distance_outliers(&mut lock, factor);
                     }
-                    // retain recently updated modules
-                    lock.retain(|_, m| {
-                        m.updated.elapsed() < Duration::from_millis(250)
-                            && m.lon.is_finite()
-                            && m.lat.is_finite()
-                    });
-                    let modules = lock.clone();
+                    if let Some(c) = calibration.as_mut() {
+                        c.add(&lock);
+                    }
+                    metrics.modules.store(lock.len() as u64, Ordering::Relaxed);
+                    let newest = lock.values().map(|m| m.updated).max();
+                    let frame = aggregator.frame(&lock);
+                    let track_ids: Vec<Option<u32>> = match &frame {
+                        Frame::Ready { keys, .. } => keys.iter().map(|k| lock[k].track).collect(),
+                        _ => Vec::new(),
+                    };
                     drop(lock);
 
-                    let detection = modules.iter().any(|(_, m)| m.drone);
-
-                    if detection {
-                        // remove outliers
-                        // if modules.len() >= 3 {
-                        //     // calculate median distance
-                        //     let sorted: Vec<f64> = modules
-                        //         .values()
-                        //         .map(|m| m.dist)
-                        //         .sorted_unstable_by(|a, b| a.total_cmp(b))
-                        //         .collect();
-                        //
-                        //     let n_sorted = sorted.len();
-                        //     let median = if n_sorted % 2 == 0 {
-                        //         let n_half = n_sorted / 2;
-                        //         (sorted[n_half - 1] + sorted[n_half]) / 2.0
-                        //     } else {
-                        //         sorted[n_sorted / 2]
-                        //     };
-                        //
-                        //     // calcualte average distance
-                        //     // let avg = sorted.iter().sum::<f64>() / n_sorted as f64;
-                        //
-                        //     // retain modules with distance within +/- 25% of median
-                        //     modules.retain(|_, m| (median - m.dist).abs() < median / 4.0);
-                        // }
-
-                        // proceed with calculating drone position if at least 3 modules retained
-                        if modules.len() < 3 {
-                            log::warn!("Not enough modules retained to compute solution");
-                        } else {
-                            let sensors: Vec<Sensor> = modules
-                                .values()
-                                .map(|m| {
-                                    let lle = Lle::<Wgs84>::new(
-                                        Degrees::new(m.lat),
-                                        Degrees::new(m.lon),
-                                        Meters::new(0.0),
-                                    );
-                                    let enu = CoordinateSystem::lle_to_enu(
-                                        &lle,
-                                        ref_lle.as_ref().unwrap(),
-                                    );
-                                    Sensor { enu, dist: m.dist }
-                                })
-                                .collect();
+                    if calibration.as_ref().is_some_and(Calibration::is_done) {
+                        noise = calibration.take().unwrap().finish();
+                        for (mac, stddev) in &noise {
+                            log::info!("Calibrated measurement noise of {mac}: {stddev:.3} m");
+                        }
+                    }
+
+                    match frame {
+                        frame @ (Frame::NoDetection | Frame::NotEnough) => {
+                            if let Frame::NoDetection = frame {
+                                log::warn!("No detection");
+                                metrics.no_detection.fetch_add(1, Ordering::Relaxed);
+                            } else {
+                                log::warn!("Not enough modules retained to compute solution");
+                                metrics.not_enough.fetch_add(1, Ordering::Relaxed);
+                            }
+                            if let Some(stale_after) = stale_after_ms
+                                && emit
+                            {
+                                for (&track, (text, computed)) in &last_fixes {
+                                    let age = computed.elapsed().as_millis();
+                                    let text = format!("{text},{},{age}", age > stale_after);
+                                    outbox.insert(track, tungstenite::Message::Text(text.into()));
+                                }
+                            }
+                        }
+                        Frame::Ready {
+                            keys: macs,
+                            mut sensors,
+                        } => {
+                            for (mac, sensor) in macs.iter().zip(sensors.iter_mut()) {
+                                sensor.stddev = noise.get(mac).map(|&stddev| stddev as Real);
+                            }
+                            if last_layout_check.is_none_or(|t| t.elapsed() >= LAYOUT_CHECK_PERIOD)
+                            {
+                                aggregate::check_layout(&sensors);
+                                last_layout_check = Some(Instant::now());
+                            }
+
+                            if let Some(secs) = reset_after_secs {
+                                let gap = last_solution.elapsed().as_secs_f64();
+                                if gap > secs {
+                                    log::info!("No solution for {gap:.1} s, resetting the filter");
+                                    for ekf in tracks.values_mut() {
+                                        ekf.reset(init_east, init_north);
+                                    }
+                                    averages.clear();
+                                    altitudes.clear();
+                                }
+                            }
+                            last_solution = Instant::now();
+
+                            // time between the newest reports of consecutive frames, for tracks
+                            // not updated before
+                            let newest = newest.unwrap();
+                            let frame_gap = newest.saturating_duration_since(last_frame);
+                            last_frame = newest;
 
-                            let dt = last_predict.elapsed().as_secs_f64();
-                            log::info!("predict dt = {dt}");
-                            let (x_pred, P_pred) = ekf.predict(dt);
-                            last_predict = Instant::now();
-                            ekf.update(x_pred, P_pred, &sensors);
+                            // route every range to a track
+                            let mut groups: BTreeMap<u32, (Vec<String>, Vec<Sensor>)> =
+                                BTreeMap::new();
+                            for ((mac, sensor), track) in
+                                macs.into_iter().zip(sensors).zip(track_ids)
+                            {
+                                let track = if multi_target {
+                                    track
+                                        .or_else(|| nearest_track(&tracks, &sensor))
+                                        .unwrap_or(0)
+                                } else {
+                                    0
+                                };
+                                let (macs, sensors) = groups.entry(track).or_default();
+                                macs.push(mac);
+                                sensors.push(sensor);
+                            }
 
-                            let enu = Enu {
-                                east: Meters::new(ekf.x_est[0]),
-                                north: Meters::new(ekf.x_est[1]),
-                                up: Meters::new(0.0),
-                            };
+                            for (track, (macs, sensors)) in groups {
+                                let ekf = tracks.entry(track).or_insert_with(new_ekf);
+                                // a track may have gone without ranges for several frames
+                                let gap = track_updates
+                                    .insert(track, newest)
+                                    .map_or(frame_gap, |t| newest.saturating_duration_since(t));
+                                let dt =
+                                    (gap.as_secs_f64() as Real).clamp(ekf::MIN_DT, ekf::MAX_DT);
+                                log::info!("predict dt = {dt}");
+                                let (x_pred, P_pred) = ekf.predict(dt);
+                                ekf.update(x_pred, P_pred, &sensors);
+                                metrics.fixes.fetch_add(1, Ordering::Relaxed);
+                                *metrics.last_fix.lock() = Some(Instant::now());
+                                let gated = ekf.last_update.gated.len() as u64;
+                                metrics.gated.fetch_add(gated, Ordering::Relaxed);
+                                for &i in &ekf.last_update.gated {
+                                    log::info!("Gated range from module {}", macs[i]);
+                                }
+                                if let Some(log_likelihood) = ekf.last_update.log_likelihood {
+                                    log::debug!("Innovation log-likelihood = {log_likelihood}");
+                                }
 
-                            let lle = CoordinateSystem::enu_to_lle(ref_lle.as_ref().unwrap(), &enu);
+                                let altitude = if fit_altitude {
+                                    if let Some(fitted) = ekf.fitted_altitude(&sensors) {
+                                        altitudes.insert(track, fitted);
+                                    }
+                                    altitudes.get(&track).copied().unwrap_or(0.0)
+                                } else {
+                                    ekf.altitude()
+                                };
+                                let (east, north) = ekf.east_north();
+                                let enu = Enu {
+                                    east: Meters::new(east as f64),
+                                    north: Meters::new(north as f64),
+                                    up: Meters::new(altitude as f64),
+                                };
+                                let enu = if smooth_window > 1 {
+                                    averages
+                                        .entry(track)
+                                        .or_insert_with(|| MovingAverage::new(smooth_window))
+                                        .push(&enu)
+                                } else {
+                                    enu
+                                };
 
-                            let _ = socket.send(tungstenite::Message::Text(
-                                format!("{},{}", lle.longitude.as_float(), lle.latitude.as_float())
-                                    .into(),
-                            ));
+                                let lle = CoordinateSystem::enu_to_lle(
+                                    aggregator.ref_lle.as_ref().unwrap(),
+                                    &enu,
+                                );
+
+                                let timestamp = SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_secs_f64();
+                                let mut text = if nmea {
+                                    nmea::gga(timestamp, &lle, ekf)
+                                } else {
+                                    format_fix(&lle, ekf, velocity, hdop, units)
+                                };
+                                if multi_target {
+                                    text = format!("{track},{text}");
+                                }
+                                let trace = ekf.P_est[(0, 0)] + ekf.P_est[(1, 1)];
+                                let converged = converged_trace.is_none_or(|max| trace <= max);
+                                if stale_after_ms.is_some() {
+                                    if converged {
+                                        last_fixes.insert(track, (text.clone(), Instant::now()));
+                                    }
+                                    text.push_str(",false,0");
+                                }
+                                let message = tungstenite::Message::Text(text.into());
+                                #[cfg(feature = "protobuf")]
+                                let message = if protobuf {
+                                    let mut point = TrackPoint::new(timestamp, &lle, ekf);
+                                    point.track = track;
+                                    tungstenite::Message::Binary(point.encode_to_vec().into())
+                                } else {
+                                    message
+                                };
+                                if !converged {
+                                    log::info!(
+                                        "Converging, position covariance trace = {trace:.1}"
+                                    );
+                                } else if emit {
+                                    outbox.insert(track, message);
+                                }
+                            }
+
+                            if let Some(path) = &checkpoint
+                                && last_checkpoint.elapsed() >= CHECKPOINT_PERIOD
+                            {
+                                save_checkpoint(
+                                    path,
+                                    aggregator.ref_lle.as_ref().unwrap(),
+                                    &tracks,
+                                );
+                                last_checkpoint = Instant::now();
+                            }
 
                             // match client
                             //     .post("http://10.66.66.1:8080/andros/publish")
@@ -188,21 +737,107 @@ pub fn run(ws_in: &str, ws_out: String) {
                             //     }
                             // }
                         }
-                    } else {
-                        log::warn!("No detection");
                     }
 
-                    let sleep_time = read_period.saturating_sub(start.elapsed());
+                    let now = Instant::now();
+                    if !outbox.is_empty() && now >= next_send {
+                        for message in std::mem::take(&mut outbox).into_values() {
+                            if let Err(err) = socket.send(message) {
+                                log::warn!("Drone WebSocket send failed: {err}, reconnecting");
+                                break 'frames;
+                            }
+                        }
+                        backoff = MIN_RECONNECT_BACKOFF;
+                        if let Some(period) = send_period {
+                            next_send += period;
+                            // start the ticks over after idling instead of catching up
+                            if next_send <= now {
+                                next_send = now + period;
+                            }
+                        }
+                    }
+
+                    let elapsed = start.elapsed();
+                    let sleep_time = if elapsed > read_period {
+                        overruns += 1;
+                        metrics.overruns.fetch_add(1, Ordering::Relaxed);
+                        if overruns % OVERRUN_WARN_FRAMES == 0 {
+                            log::warn!(
+                                "{overruns} frames in a row overran the {} ms read period, \
+                                 the last took {} ms",
+                                read_period.as_millis(),
+                                elapsed.as_millis()
+                            );
+                        }
+                        if skip_late_frames {
+                            // realign to the period, dropping the frames already missed
+                            let late = elapsed.as_nanos() % read_period.as_nanos();
+                            read_period - Duration::from_nanos(late as u64)
+                        } else {
+                            Duration::ZERO
+                        }
+                    } else {
+                        if overruns >= OVERRUN_WARN_FRAMES {
+                            log::info!("Frames are back within the read period");
+                        }
+                        overruns = 0;
+                        read_period - elapsed
+                    };
                     // log::debug!("Sleep for {}", sleep_time.as_micros());
-                    sleep(sleep_time);
+                    leader_reported = match &leader_rx {
+                        // wake up early to solve and emit as soon as the leader reports
+                        Some(rx) => {
+                            let reported = rx.recv_timeout(sleep_time).is_ok();
+                            rx.try_iter().count();
+                            reported
+                        }
+                        None => {
+                            sleep(sleep_time);
+                            false
+                        }
+                    };
                 }
             }
         }
     });
 
-    let server = TcpListener::bind(ws_in).unwrap();
-    for stream in server.incoming() {
+    let read_timeout = Duration::from_secs_f64(read_timeout_secs);
+    while !shutdown.load(Ordering::SeqCst) {
+        let (stream, addr) = match server.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                sleep(ACCEPT_POLL_PERIOD);
+                continue;
+            }
+            Err(err) => {
+                log::error!("Error accepting WebSocket connection: {err}");
+                continue;
+            }
+        };
+        // only the accept loop opens connections, so the count cannot grow past the check
+        if metrics.connections.load(Ordering::Relaxed) >= max_connections {
+            log::warn!("Refusing connection from {addr}, {max_connections} already open");
+            continue;
+        }
+        // a half-open connection from a crashed module times out instead of holding its thread
+        let stream = match stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_read_timeout(Some(read_timeout)))
+            .and_then(|()| tls::wrap(stream, tls_config.as_ref()))
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("Error configuring WebSocket connection: {err}");
+                continue;
+            }
+        };
         let modules = modules.clone();
+        let metrics = metrics.clone();
+        let leader = leader.clone();
+        let leader_tx = leader_tx.clone();
+        let recorder = recorder.clone();
+        let roster = roster.clone();
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
         spawn(move || {
             // let callback = |req: &Request, mut response: Response| {
             //     println!("Received a new ws handshake");
@@ -219,47 +854,408 @@ pub fn run(ws_in: &str, ws_out: String) {
             //
             //     Ok(response)
             // };
-            let mut websocket = accept(stream.unwrap()).unwrap();
-            log::info!("WebSocket connection accepted");
+            let mut websocket = match accept(stream) {
+                Ok(websocket) => websocket,
+                Err(err) => {
+                    log::warn!("WebSocket handshake with {addr} failed: {err}");
+                    metrics.connections.fetch_sub(1, Ordering::Relaxed);
+                    return;
+                }
+            };
+            log::info!("WebSocket connection from {addr} accepted");
 
             loop {
-                let Ok(msg) = websocket.read() else {
-                    continue;
+                let msg = match websocket.read() {
+                    Ok(msg) => msg,
+                    Err(tungstenite::Error::Io(err))
+                        if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                    {
+                        log::warn!(
+                            "Closing the connection from {addr}, silent for {read_timeout_secs} s"
+                        );
+                        break;
+                    }
+                    Err(
+                        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed,
+                    ) => {
+                        log::info!("WebSocket connection from {addr} closed");
+                        break;
+                    }
+                    Err(err) => {
+                        log::warn!("Error reading from {addr}, closing the connection: {err}");
+                        break;
+                    }
                 };
                 if msg.is_binary() || msg.is_text() {
                     // log::info!("Message: {msg}");
 
-                    let text = msg.to_text().unwrap();
-                    let fields: Vec<&str> = text.split("|").collect();
-
-                    let (mac, ip, lat, lon, drone, dist) = (
-                        fields[0],
-                        fields[1],
-                        fields[2].parse::<f64>().unwrap(),
-                        fields[3].parse::<f64>().unwrap(),
-                        fields[4].parse::<bool>().unwrap(),
-                        fields[5].parse::<f64>().unwrap(),
-                    );
-
-                    modules.lock().insert(
-                        mac.to_owned(),
-                        Module {
-                            // mac: mac.to_owned(),
-                            // ip: ip.to_owned(),
-                            lat,
-                            lon,
-                            alt: 0.0,
-                            drone,
-                            dist,
-                            updated: Instant::now(),
-                        },
-                    );
+                    let Ok(text) = msg.to_text() else {
+                        log::warn!("Ignoring message that is not valid UTF-8");
+                        continue;
+                    };
+                    let (mac, mut module) = match parse_message(text, roster.is_none()) {
+                        Ok(report) => report,
+                        Err(err) => {
+                            log::warn!("Ignoring malformed message {text:?}: {err}");
+                            metrics.malformed.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                    };
+                    if let Some(roster) = &roster {
+                        let Some(position) = roster.lock().position(&mac) else {
+                            continue;
+                        };
+                        (module.lat, module.lon, module.alt) =
+                            (position.lat, position.lon, position.alt);
+                    }
+
+                    if let Some(recorder) = &recorder
+                        && let Err(err) = recorder.lock().record(&mac, &module)
+                    {
+                        log::error!("Error recording module report: {err}");
+                    }
+
+                    let is_leader = leader.as_deref() == Some(mac.as_str());
+                    let mut modules = modules.lock();
+                    if range_smoothing < 1.0 && module.drone && module.dist > 0.0 {
+                        let previous = modules
+                            .get(&mac)
+                            .filter(|m| {
+                                m.drone
+                                    && m.dist > 0.0
+                                    && m.updated.elapsed() < aggregate::RETAIN_PERIOD
+                            })
+                            .map(|m| m.dist);
+                        module.dist = smoothing::ema(previous, module.dist, range_smoothing);
+                    }
+                    modules.insert(mac, module);
+                    drop(modules);
+                    if is_leader {
+                        let _ = leader_tx.send(());
+                    }
 
                     // log::debug!(
                     //     "Message {{ mac: {mac}, ip: {ip}, lat: {lat}, lon: {lon}, drone: {drone}, dist: {dist} }}"
                     // )
                 }
             }
+            metrics.connections.fetch_sub(1, Ordering::Relaxed);
         });
     }
+
+    if compute.join().is_err() {
+        log::error!("Compute thread panicked");
+    }
+    log::info!("Processor stopped");
+}
+
+fn save_checkpoint(path: &Path, ref_lle: &Lle<Wgs84>, tracks: &HashMap<u32, Ekf>) {
+    if let Err(err) = Checkpoint::new(ref_lle, tracks).save(path) {
+        log::warn!("Failed to write checkpoint {}: {err}", path.display());
+    }
+}
+
+/// Formats an estimate as the `lon,lat,std_east,std_north[,speed,bearing][,hdop]` text message,
+/// with the standard deviations and speed in `units`.
+pub(crate) fn format_fix(
+    lle: &Lle<Wgs84>,
+    ekf: &Ekf,
+    velocity: bool,
+    hdop: bool,
+    units: Units,
+) -> String {
+    let (std_east, std_north) = ekf.position_stddev();
+    let (std_east, std_north) =
+        (units.from_meters(std_east as f64), units.from_meters(std_north as f64));
+    let mut text =
+        format!("{},{},{std_east},{std_north}", lle.longitude.as_float(), lle.latitude.as_float());
+    if velocity {
+        let (vel_east, vel_north) = ekf.velocity();
+        let speed = units.from_meters(vel_east.hypot(vel_north) as f64);
+        let bearing = vel_east.atan2(vel_north).to_degrees().rem_euclid(360.0);
+        text.push_str(&format!(",{speed},{bearing}"));
+    }
+    if hdop {
+        text.push(',');
+        if let Some(hdop) = ekf.hdop() {
+            text.push_str(&hdop.to_string());
+        }
+    }
+    text
+}
+
+/// Drops modules whose distance is at least `factor` times the median distance away from it.
+///
+/// Nothing is dropped with fewer than 3 modules, and the furthest outliers are dropped first
+/// so that at least 3 modules remain.
+fn reject_distance_outliers(modules: &mut HashMap<String, Module>, factor: f64) {
+    if modules.len() < 3 {
+        return;
+    }
+    let dists: Vec<f64> = modules.values().map(|m| m.dist).collect();
+    let median = outliers::median(&dists).unwrap();
+
+    let mut rejected: Vec<(String, f64)> = modules
+        .iter()
+        .map(|(mac, m)| (mac.clone(), (median - m.dist).abs()))
+        .filter(|(_, deviation)| *deviation >= median * factor)
+        .collect();
+    rejected.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+    for (mac, deviation) in rejected {
+        if modules.len() <= 3 {
+            break;
+        }
+        log::info!("Dropping outlier module {mac}, {deviation:.1} m from the median distance");
+        modules.remove(&mac);
+    }
+}
+
+/// Module report in the JSON message format, `ip` and unknown fields are ignored.
+#[derive(Deserialize)]
+struct JsonReport {
+    mac: String,
+    /// Position of the module, which may be left out with `--roster-url`.
+    #[serde(default)]
+    lat: Option<f64>,
+    #[serde(default)]
+    lon: Option<f64>,
+    drone: bool,
+    dist: f64,
+    #[serde(default)]
+    track: Option<u32>,
+}
+
+/// Parses a module report, either a JSON object or `mac|ip|lat|lon|drone|dist[|track]`.
+///
+/// Without `with_position` the position in the report is ignored, and may be empty, leaving the
+/// module at 0, 0 for the caller to place from the roster.
+fn parse_message(text: &str, with_position: bool) -> Result<(String, Module), String> {
+    if text.trim_start().starts_with('{') {
+        let report: JsonReport = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let (lat, lon) = if with_position {
+            let (lat, lon) = report.lat.zip(report.lon).ok_or("missing lat or lon")?;
+            aggregate::check_position(lat, lon)?;
+            (lat, lon)
+        } else {
+            (0.0, 0.0)
+        };
+        let module = Module {
+            lat,
+            lon,
+            alt: 0.0,
+            drone: report.drone,
+            dist: report.dist,
+            updated: Instant::now(),
+            track: report.track,
+        };
+        return Ok((report.mac, module));
+    }
+
+    let fields: Vec<&str> = text.split("|").collect();
+    if fields.len() < 6 {
+        return Err(format!("expected at least 6 fields, got {}", fields.len()));
+    }
+    let parse_f64 = |i: usize, name: &str| {
+        fields[i]
+            .parse::<f64>()
+            .map_err(|e| format!("invalid {name} {:?}: {e}", fields[i]))
+    };
+
+    let (mac, _ip, dist) = (fields[0], fields[1], parse_f64(5, "dist")?);
+    let (lat, lon) = if with_position {
+        let (lat, lon) = (parse_f64(2, "lat")?, parse_f64(3, "lon")?);
+        aggregate::check_position(lat, lon)?;
+        (lat, lon)
+    } else {
+        (0.0, 0.0)
+    };
+    let drone = fields[4]
+        .parse::<bool>()
+        .map_err(|e| format!("invalid drone {:?}: {e}", fields[4]))?;
+    // optional id of the drone the distance belongs to
+    let track = match fields.get(6) {
+        Some(t) => Some(
+            t.parse::<u32>()
+                .map_err(|e| format!("invalid track {t:?}: {e}"))?,
+        ),
+        None => None,
+    };
+
+    let module = Module {
+        // mac: mac.to_owned(),
+        // ip: ip.to_owned(),
+        lat,
+        lon,
+        alt: 0.0,
+        drone,
+        dist,
+        updated: Instant::now(),
+        track,
+    };
+    Ok((mac.to_owned(), module))
+}
+
+/// Track whose predicted range to `sensor` is closest to the measured one.
+fn nearest_track(tracks: &HashMap<u32, Ekf>, sensor: &Sensor) -> Option<u32> {
+    tracks
+        .iter()
+        .map(|(&id, ekf)| (id, (ekf.range_to(sensor) - sensor.dist).abs()))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(id, _)| id)
+}
+
+/// Estimates the measurement noise of each module from its readings during a calibration period.
+///
+/// The period starts with the first reading.
+struct Calibration {
+    duration: Duration,
+    end: Option<Instant>,
+    samples: HashMap<String, (Instant, Vec<f64>)>,
+}
+
+impl Calibration {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            end: None,
+            samples: HashMap::new(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.end.is_some_and(|end| Instant::now() >= end)
+    }
+
+    /// Records the distances of modules that reported since the last call.
+    fn add(&mut self, modules: &HashMap<String, Module>) {
+        if self.end.is_none() && !self.samples.is_empty() {
+            self.end = Some(Instant::now() + self.duration);
+        }
+        for (mac, m) in modules {
+            if !m.drone || m.dist <= 0.0 {
+                continue;
+            }
+            let (updated, dists) = self
+                .samples
+                .entry(mac.clone())
+                .or_insert_with(|| (m.updated, Vec::new()));
+            if dists.is_empty() || m.updated > *updated {
+                *updated = m.updated;
+                dists.push(m.dist);
+            }
+        }
+    }
+
+    /// Standard deviation of the readings of every module with at least two of them.
+    fn finish(self) -> HashMap<String, f64> {
+        self.samples
+            .into_iter()
+            .filter(|(_, (_, dists))| dists.len() >= 2)
+            .map(|(mac, (_, dists))| {
+                let n = dists.len() as f64;
+                let mean = dists.iter().sum::<f64>() / n;
+                let var = dists.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (n - 1.0);
+                (mac, var.sqrt().max(MIN_CALIBRATED_STDDEV))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Modules reporting the drone at each of `dists`, keyed by their index.
+    fn modules(dists: &[f64]) -> HashMap<String, Module> {
+        dists
+            .iter()
+            .enumerate()
+            .map(|(i, &dist)| {
+                let module = Module {
+                    lat: 52.5,
+                    lon: 16.7,
+                    alt: 0.0,
+                    drone: true,
+                    dist,
+                    updated: Instant::now(),
+                    track: None,
+                };
+                (i.to_string(), module)
+            })
+            .collect()
+    }
+
+    fn sorted_keys(modules: &HashMap<String, Module>) -> Vec<&str> {
+        let mut keys: Vec<&str> = modules.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn drops_distance_outliers() {
+        let mut m = modules(&[100.0, 105.0, 98.0, 102.0, 400.0]);
+        reject_distance_outliers(&mut m, 0.25);
+        assert_eq!(sorted_keys(&m), ["0", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn keeps_fewer_than_3_modules_and_at_least_3() {
+        let mut m = modules(&[100.0, 400.0]);
+        reject_distance_outliers(&mut m, 0.25);
+        assert_eq!(m.len(), 2);
+        // the furthest outlier goes first, the next one is kept to leave 3
+        let mut m = modules(&[100.0, 102.0, 300.0, 900.0]);
+        reject_distance_outliers(&mut m, 0.25);
+        assert_eq!(sorted_keys(&m), ["0", "1", "2"]);
+    }
+
+    #[test]
+    fn parses_pipe_and_json_reports() {
+        let (mac, m) = parse_message("aa:01|10.0.0.1|52.5|16.7|true|120.5|3", true).unwrap();
+        assert_eq!(mac, "aa:01");
+        assert_eq!((m.lat, m.lon, m.drone, m.dist, m.track), (52.5, 16.7, true, 120.5, Some(3)));
+
+        let json = r#"{"mac": "aa:02", "lat": 52.5, "lon": 16.7, "drone": false, "dist": 0}"#;
+        let (mac, m) = parse_message(json, true).unwrap();
+        assert_eq!(mac, "aa:02");
+        assert_eq!((m.lat, m.lon, m.drone, m.dist, m.track), (52.5, 16.7, false, 0.0, None));
+    }
+
+    #[test]
+    fn rejects_bad_json() {
+        assert!(parse_message(r#"{"mac": "aa:01", "lat": 52.5"#, true).is_err());
+        assert!(parse_message(r#"{"mac": "aa:01", "drone": "yes", "dist": 1}"#, true).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        assert!(parse_message("aa:01|10.0.0.1|52.5|16.7|true", true).is_err());
+        assert!(parse_message("aa:01|10.0.0.1|52.5||true|120.5", true).is_err());
+        assert!(parse_message(r#"{"mac": "aa:01", "lat": 52.5, "drone": true}"#, true).is_err());
+        let no_lon = r#"{"mac": "aa:01", "lat": 52.5, "drone": true, "dist": 1}"#;
+        assert!(parse_message(no_lon, true).is_err());
+        // the roster places the module instead
+        assert!(parse_message(no_lon, false).is_ok());
+        assert!(parse_message("aa:01|10.0.0.1|||true|120.5", false).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        assert!(parse_message("aa:01|10.0.0.1|abc|16.7|true|120.5", true).is_err());
+        assert!(parse_message("aa:01|10.0.0.1|52.5|16.7|maybe|120.5", true).is_err());
+        assert!(parse_message("aa:01|10.0.0.1|52.5|16.7|true|far", true).is_err());
+    }
+
+    #[test]
+    fn rejects_non_finite_coordinates() {
+        for (lat, lon) in [
+            ("NaN", "16.7"),
+            ("52.5", "inf"),
+            ("-inf", "16.7"),
+            ("91", "16.7"),
+        ] {
+            let text = format!("aa:01|10.0.0.1|{lat}|{lon}|true|120.5");
+            assert!(parse_message(&text, true).is_err(), "{text}");
+        }
+    }
 }
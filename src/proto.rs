@@ -0,0 +1,44 @@
+//! Protobuf encoding of track estimates, mirroring `proto/track.proto`.
+use geoconv::{Lle, Wgs84};
+use prost::Message;
+
+use crate::ekf::Ekf;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TrackPoint {
+    #[prost(double, tag = "1")]
+    pub timestamp: f64,
+    #[prost(double, tag = "2")]
+    pub lat: f64,
+    #[prost(double, tag = "3")]
+    pub lon: f64,
+    #[prost(double, tag = "4")]
+    pub alt: f64,
+    #[prost(double, tag = "5")]
+    pub vel_east: f64,
+    #[prost(double, tag = "6")]
+    pub vel_north: f64,
+    #[prost(double, repeated, tag = "7")]
+    pub covariance: Vec<f64>,
+    #[prost(uint32, tag = "8")]
+    pub track: u32,
+    #[prost(double, optional, tag = "9")]
+    pub hdop: Option<f64>,
+}
+
+impl TrackPoint {
+    pub fn new(timestamp: f64, lle: &Lle<Wgs84>, ekf: &Ekf) -> Self {
+        let (vel_east, vel_north) = ekf.velocity();
+        Self {
+            timestamp,
+            lat: lle.latitude.as_float(),
+            lon: lle.longitude.as_float(),
+            alt: lle.elevation.as_float(),
+            vel_east: vel_east as f64,
+            vel_north: vel_north as f64,
+            covariance: ekf.P_est.transpose().iter().map(|&v| v as f64).collect(),
+            track: 0,
+            hdop: ekf.hdop().map(|h| h as f64),
+        }
+    }
+}
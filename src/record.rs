@@ -0,0 +1,111 @@
+//! Recording of the module reports received by the processor, for replaying field sessions with
+//! `location-sim --replay`.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+use crate::aggregate::Module;
+
+const HEADER: &str = "time,mac,lat,lon,alt,drone,dist,track";
+
+/// A row of a recording, as written by [`Recorder::record`].
+#[derive(Deserialize)]
+pub struct Report {
+    /// Seconds since the UNIX epoch.
+    pub time: f64,
+    pub mac: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    pub drone: bool,
+    pub dist: f64,
+}
+
+/// Appends every valid report to a CSV file, rotating it by size or age.
+///
+/// A full file is renamed to `<path>.<n>`, numbering upwards from the files already there, and
+/// a new one is started at `path`. An existing recording at `path` is appended to.
+pub struct Recorder {
+    path: PathBuf,
+    out: LineWriter<File>,
+    /// Bytes written to the current file.
+    written: u64,
+    opened: Instant,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    rotations: u32,
+}
+
+impl Recorder {
+    pub fn create(path: PathBuf, max_bytes: u64, max_age: Option<Duration>) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut rotations = 0;
+        while rotated_path(&path, rotations + 1).exists() {
+            rotations += 1;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        let mut recorder = Self {
+            out: LineWriter::new(file),
+            path,
+            written,
+            opened: Instant::now(),
+            max_bytes,
+            max_age,
+            rotations,
+        };
+        if written == 0 {
+            recorder.write_line(HEADER)?;
+        }
+        Ok(recorder)
+    }
+
+    /// Writes a row for the report of `mac`, timestamped in seconds since the UNIX epoch.
+    pub fn record(&mut self, mac: &str, module: &Module) -> io::Result<()> {
+        if self.written >= self.max_bytes
+            || self.max_age.is_some_and(|age| self.opened.elapsed() >= age)
+        {
+            self.rotate()?;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let track = module.track.map(|t| t.to_string()).unwrap_or_default();
+        self.write_line(&format!(
+            "{time},{mac},{},{},{},{},{},{track}",
+            module.lat, module.lon, module.alt, module.drone, module.dist
+        ))
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.out, "{line}")?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.rotations += 1;
+        let rotated = rotated_path(&self.path, self.rotations);
+        std::fs::rename(&self.path, &rotated)?;
+        log::info!("Rotated the recording to {}", rotated.display());
+        self.out = LineWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        self.opened = Instant::now();
+        self.write_line(HEADER)
+    }
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    rotated.into()
+}
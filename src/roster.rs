@@ -0,0 +1,127 @@
+//! Module positions fetched from an HTTP endpoint, the single source of truth for where each
+//! module is instead of the positions in its reports.
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::aggregate;
+
+/// Longest wait for the roster endpoint to connect, and then for each read.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Position of one module in the roster.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+    /// Elevation of the module in meters.
+    #[serde(default)]
+    pub alt: f64,
+}
+
+/// Latest roster, with the MACs reported since it was fetched that it does not list.
+pub struct Roster {
+    positions: HashMap<String, Position>,
+    unknown: HashSet<String>,
+}
+
+impl Roster {
+    pub fn new(positions: HashMap<String, Position>) -> Self {
+        Self {
+            positions,
+            unknown: HashSet::new(),
+        }
+    }
+
+    /// Position of the module with `mac`, logging a MAC missing from the roster once per fetch.
+    pub fn position(&mut self, mac: &str) -> Option<Position> {
+        let position = self.positions.get(mac).copied();
+        if position.is_none() && self.unknown.insert(mac.to_owned()) {
+            log::warn!("Module {mac} is not in the roster, ignoring its reports");
+        }
+        position
+    }
+}
+
+/// Fetches the roster from `url`, a JSON object mapping each MAC to its `lat`, `lon` and
+/// optional `alt`. Modules with an invalid position are logged and left out.
+pub fn fetch(url: &str) -> Result<HashMap<String, Position>, String> {
+    let body = http_get(url)?;
+    let mut positions: HashMap<String, Position> =
+        serde_json::from_str(&body).map_err(|err| format!("invalid roster: {err}"))?;
+    positions.retain(|mac, p| match aggregate::check_position(p.lat, p.lon) {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("Roster module {mac}: {err}, leaving it out");
+            false
+        }
+    });
+    Ok(positions)
+}
+
+/// Fetches the roster from `url` again every `period` from a background thread, keeping the
+/// previous one when a fetch fails.
+pub fn refresh(url: String, period: Duration, roster: Arc<Mutex<Roster>>) {
+    spawn(move || {
+        loop {
+            sleep(period);
+            match fetch(&url) {
+                Ok(positions) => {
+                    log::debug!("Fetched {} module positions from {url}", positions.len());
+                    *roster.lock() = Roster::new(positions);
+                }
+                Err(err) => log::warn!("Failed to refresh the module roster from {url}: {err}"),
+            }
+        }
+    });
+}
+
+/// Body of a `GET` of an `http://host[:port][/path]` URL.
+fn http_get(url: &str) -> Result<String, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("{url} is not an http:// URL"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{host}:80")
+    };
+    let addr = addr
+        .to_socket_addrs()
+        .map_err(|err| format!("resolving {host}: {err}"))?
+        .next()
+        .ok_or_else(|| format!("{host} has no address"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, FETCH_TIMEOUT).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(FETCH_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    // HTTP/1.0 so that the body is neither chunked nor kept open after the response
+    write!(stream, "GET {path} HTTP/1.0\r\nHost: {host}\r\nAccept: application/json\r\n\r\n")
+        .map_err(|e| e.to_string())?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("malformed HTTP response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("unexpected response {status:?}"));
+    }
+    Ok(body.to_owned())
+}
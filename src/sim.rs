@@ -0,0 +1,2175 @@
+//! Offline replay of recorded module distances through the filter.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, sync_channel},
+    },
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+use flate2::read::GzDecoder;
+use geoconv::{CoordinateSystem, Degrees, Enu, Lle, Meters, Wgs84};
+use nalgebra::DMatrix;
+#[cfg(feature = "protobuf")]
+use prost::Message;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::{self, Aggregator, Frame, Module};
+use crate::ekf::{
+    self, Ekf, EkfConfig, FilterStep, MotionModel, RangeModel, Real, Sensor, UpdateInfo,
+};
+use crate::error::SimError;
+#[cfg(feature = "protobuf")]
+use crate::proto;
+use crate::record::Report;
+use crate::smoothing::{self, MovingAverage};
+use crate::units::Units;
+use crate::{outliers, processor, utm};
+
+/// Rows read ahead of the filter.
+const ROW_BUFFER: usize = 256;
+
+/// Two-sided 95% bounds of the chi-square distribution with 2 degrees of freedom, which the
+/// NEES of a consistent filter falls within in 95% of the steps.
+const NEES_BOUNDS: (Real, Real) = (0.0506, 7.3778);
+
+/// Distance file names, capturing the module number.
+static DIST_FILE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r".*\D(\d+)\.csv(\.gz)?$").unwrap());
+
+/// Input files and options of a simulation run.
+#[derive(Clone, clap::Args)]
+pub struct LocationSimArgs {
+    #[arg(
+        long,
+        required_unless_present_any = ["batch", "replay"],
+        default_value = "",
+        hide_default_value = true
+    )]
+    pub input_dir: String,
+    #[arg(
+        long,
+        required_unless_present_any = ["batch", "replay"],
+        default_value = "",
+        hide_default_value = true
+    )]
+    pub modules_csv: String,
+    #[arg(
+        long,
+        required_unless_present = "batch",
+        default_value = "",
+        hide_default_value = true
+    )]
+    pub output_csv: String,
+    #[arg(long)]
+    pub max_dist: Option<Real>,
+    /// Print the F, Q, H and R matrices of the first full update
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "continue")]
+    pub dump_matrices: Option<DumpMatrices>,
+    /// Reject distances further than this fraction of the median distance (e.g. 0.25)
+    #[arg(long)]
+    pub outlier_band: Option<f64>,
+    /// Weight of each new distance in a per-module exponential moving average applied before
+    /// the filter, e.g. 0.3 to damp spikes, 1 to use the distances as read
+    #[arg(long, default_value_t = 1.0, value_parser = smoothing::parse_alpha)]
+    pub range_smoothing: f64,
+    /// Nominal drone altitude in meters, used to convert slant ranges into horizontal ranges
+    /// (or as the initial altitude in 3D mode)
+    #[arg(long)]
+    pub drone_alt: Option<Real>,
+    /// Latitude of the ENU reference, the centroid of the modules by default
+    #[arg(long, requires = "ref_lon")]
+    pub ref_lat: Option<f64>,
+    /// Longitude of the ENU reference
+    #[arg(long, requires = "ref_lat")]
+    pub ref_lon: Option<f64>,
+    /// Add a column listing the modules whose measurements were used in each step
+    #[arg(long)]
+    pub annotate_modules: bool,
+    /// Drop up to this many sensors to recover from a singular innovation covariance
+    #[arg(long, default_value_t = 0)]
+    pub singular_retries: usize,
+    /// Interpolate positions across steps without a measurement update and flag them
+    #[arg(long)]
+    pub interpolate_gaps: bool,
+    /// Check the filter invariants after every update and log violations
+    #[arg(long)]
+    pub strict: bool,
+    /// Reject ranges further than this many meters from the predicted range
+    #[arg(long)]
+    pub gate_meters: Option<Real>,
+    /// Feed rows through the processor's aggregation, treating each one as a module report
+    #[arg(long)]
+    pub processor_mode: bool,
+    /// Add a column with the log-likelihood of each step's innovation
+    #[arg(long)]
+    pub likelihood: bool,
+    /// Add a column with the horizontal dilution of precision of each step's sensor geometry
+    #[arg(long)]
+    pub hdop: bool,
+    /// Pair distance files with modules by the number in the file name instead of by position
+    #[arg(long)]
+    pub match_by_id: bool,
+    /// Simplify the output track, dropping points within this many meters of a straight segment
+    #[arg(long)]
+    pub decimate: Option<f64>,
+    /// Consider the drone absent after this many consecutive steps without a measurement update
+    #[arg(long)]
+    pub max_iter_without_detection: Option<usize>,
+    /// What to do once the drone is considered absent
+    #[arg(
+        long,
+        value_enum,
+        default_value = "stop",
+        requires = "max_iter_without_detection"
+    )]
+    pub on_no_detection: NoDetection,
+    /// Estimate altitude with a 3D filter, seeded from --drone-alt
+    #[arg(long)]
+    pub three_d: bool,
+    /// Fit the altitude to the slant ranges after each 2D update when the modules are at
+    /// different altitudes
+    #[arg(long, conflicts_with = "three_d")]
+    pub fit_altitude: bool,
+    /// Altitude in meters the drone is expected to fly at, added to every 3D update as a
+    /// measurement of the estimated altitude
+    #[arg(long, requires = "three_d")]
+    pub expected_altitude: Option<Real>,
+    /// Standard deviation of --expected-altitude in meters
+    #[arg(long, default_value_t = ekf::EXPECTED_ALTITUDE_STDDEV, requires = "expected_altitude")]
+    pub expected_altitude_stddev: Real,
+    /// Initial standard deviation of each position component in meters
+    #[arg(long, default_value_t = ekf::INIT_POS_STDDEV)]
+    pub init_pos_stddev: Real,
+    /// Standard deviation of the acceleration driving the process noise in m/s^2
+    #[arg(long, default_value_t = ekf::PROCESS_NOISE_STDDEV)]
+    pub process_noise: Real,
+    /// Standard deviation of a range measurement in meters
+    #[arg(long, default_value_t = ekf::MEASUREMENT_STDDEV)]
+    pub measurement_noise: Real,
+    /// Standard deviation of a bearing measurement in degrees, for distance files with a
+    /// `bearing` column
+    #[arg(long, default_value_t = ekf::BEARING_STDDEV.to_degrees())]
+    pub bearing_noise: Real,
+    /// Estimator of the positions, `wls` to solve each frame on its own by weighted least
+    /// squares as a baseline for the filter, leaving the velocities empty
+    #[arg(long, value_enum, default_value = "ekf")]
+    pub solver: Solver,
+    /// Kinematic model of the filter
+    #[arg(long, value_enum, default_value = "constant-velocity")]
+    pub motion_model: MotionModel,
+    /// Form of the range measurement equation, squared ranges stay well-behaved with the drone
+    /// over a module
+    #[arg(long, value_enum, default_value = "range")]
+    pub range_model: RangeModel,
+    /// Smallest predicted range in meters, keeping the range Jacobian finite with the estimate
+    /// on a module; raise it (e.g. to 1) if the filter jumps as the drone flies over a module
+    #[arg(long, default_value_t = ekf::RANGE_EPSILON)]
+    pub range_epsilon: Real,
+    /// Module whose range the distances of the others are differences to with
+    /// `--range-model tdoa`
+    #[arg(
+        long,
+        required_if_eq("range_model", "tdoa"),
+        conflicts_with_all = ["processor_mode", "replay"]
+    )]
+    pub tdoa_reference: Option<i32>,
+    /// Reject ranges whose normalized innovation squared exceeds this (e.g. 6.63 for 99%)
+    #[arg(long)]
+    pub chi2_gate: Option<Real>,
+    /// Merge modules closer than this many meters to each other into one, averaging their ranges
+    #[arg(long)]
+    pub merge_distance: Option<Real>,
+    /// Limit the estimated horizontal speed to this many m/s
+    #[arg(long)]
+    pub max_speed: Option<Real>,
+    /// Update from the intersection of two ranges nearest to the prediction when only two are
+    /// available, with the range noise inflated by this factor (e.g. 3)
+    #[arg(long)]
+    pub two_sensor_factor: Option<Real>,
+    /// Raise the process noise by up to this factor while the innovations are larger than
+    /// expected, e.g. 100 to follow maneuvers without retuning --process-noise
+    #[arg(long)]
+    pub max_process_noise_scale: Option<Real>,
+    /// Output positions smoothed by a Rauch-Tung-Striebel backward pass
+    ///
+    /// Keeps the state and covariance of every step in memory until the end of the input.
+    #[arg(long)]
+    pub smooth: bool,
+    /// Output the mean of the last N estimated positions, 1 for no averaging
+    #[arg(
+        long,
+        default_value_t = 1,
+        conflicts_with = "smooth",
+        value_parser = smoothing::parse_window
+    )]
+    pub smooth_window: usize,
+    /// Write the number of ranges, the mean and largest absolute range innovation and the
+    /// innovation of each module to this CSV, for every measurement update, in meters (squared
+    /// meters with `--range-model squared-range`)
+    #[arg(long)]
+    pub innovation_csv: Option<String>,
+    /// Write the upper triangle of the state covariance after every step to this CSV, row by
+    /// row, with columns named `<row state>:<column state>` such as `east:vel_east`
+    #[arg(long)]
+    pub covariance_csv: Option<String>,
+    /// True drone positions with `lat,lon` columns, one row per input row, to report the
+    /// normalized estimation error squared (NEES) of the filter against
+    #[arg(long)]
+    pub truth_csv: Option<String>,
+    /// Skip the rows before this one, counting from 0, without running the filter
+    #[arg(long, default_value_t = 0)]
+    pub start_frame: usize,
+    /// Stop before this row
+    #[arg(long)]
+    pub end_frame: Option<usize>,
+    /// Check the modules CSV and distance files, print a summary and exit without running the
+    /// filter
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Seconds between input rows, used for rows without a timestamp
+    #[arg(long, default_value_t = 0.05, value_parser = parse_positive)]
+    pub dt: f64,
+    /// Pace the steps by their timestamps (or --dt apart) instead of running as fast as possible
+    #[arg(long)]
+    pub realtime: bool,
+    /// Replay speed multiplier in --realtime mode
+    #[arg(long, default_value_t = 1.0, requires = "realtime", value_parser = parse_positive)]
+    pub speed: f64,
+    /// Also send each estimate to this WebSocket address in the processor's text format
+    #[arg(long, requires = "realtime")]
+    pub ws_out: Option<String>,
+    /// Format of the file written to --output-csv
+    #[arg(long, value_enum, default_value = "csv")]
+    pub output_format: OutputFormat,
+    /// Coordinates of the CSV output, UTM uses the zone of the ENU reference for the whole track
+    #[arg(long, value_enum, default_value = "wgs84")]
+    pub output_crs: OutputCrs,
+    /// Unit of the altitudes, ENU/NED positions, standard deviations and velocities in the CSV
+    /// output and --ws-out messages, UTM coordinates stay in meters
+    #[arg(long, value_enum, default_value = "meters")]
+    pub units: Units,
+    /// Simulate every recording listed in this CSV with `input_dir,output_csv,modules_csv`
+    /// columns, using the other options for all of them
+    #[arg(long, conflicts_with_all = ["input_dir", "modules_csv", "output_csv"])]
+    pub batch: Option<String>,
+    /// Replay a file written by the processor's --record instead of --input-dir and
+    /// --modules-csv, through the processor's aggregation every --dt seconds, numbering the
+    /// modules from 1 in the order of their MAC addresses
+    #[arg(
+        long,
+        conflicts_with_all = ["input_dir", "modules_csv", "batch", "match_by_id", "dry_run"]
+    )]
+    pub replay: Option<String>,
+    /// Number of --batch recordings simulated in parallel
+    #[arg(long, default_value_t = 1, requires = "batch", value_parser = parse_jobs)]
+    pub jobs: usize,
+    /// Also write each estimate as a length-delimited protobuf `TrackPoint` to this file
+    #[cfg(feature = "protobuf")]
+    #[arg(long)]
+    pub protobuf: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// One row per estimate
+    Csv,
+    /// FeatureCollection with the trajectory as a LineString and a Point per estimate
+    Geojson,
+    /// Placemark with a gx:Track if the input has timestamps, otherwise a LineString
+    Kml,
+    /// Track with a point per estimate, with elevations in 3D mode and times if the input has
+    /// timestamps
+    Gpx,
+    /// One JSON object per line and estimate, with every field in meters and WGS84
+    Jsonl,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DumpMatrices {
+    /// Keep running after printing the matrices
+    Continue,
+    /// Stop the simulation after printing the matrices
+    Exit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputCrs {
+    /// `lat,lon,alt` columns in degrees and meters
+    Wgs84,
+    /// `easting,northing,zone,alt` columns in meters, with zones like `33N`, all in the zone of
+    /// the reference longitude, stated on a `#` line above the header
+    Utm,
+    /// `east,north,up` columns in meters from the ENU reference
+    Enu,
+    /// `north,east,down` columns in meters from the ENU reference
+    Ned,
+}
+
+/// Position columns of the CSV output, resolved from [`OutputCrs`].
+#[derive(Clone, Copy)]
+enum PositionColumns {
+    Wgs84,
+    Utm(u8),
+    Enu,
+    Ned,
+}
+
+/// How the position of an estimate was obtained, written to the `fix_type` column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FixType {
+    /// A measurement update was applied.
+    Measured,
+    /// Some sensors passed filtering but too few for an update, so the prediction was kept.
+    Coasted,
+    /// No sensor passed filtering.
+    None,
+}
+
+impl FixType {
+    fn of(update: &UpdateInfo) -> Self {
+        if update.H.is_some() {
+            Self::Measured
+        } else if update.used.is_empty() {
+            Self::None
+        } else {
+            Self::Coasted
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Measured => "measured",
+            Self::Coasted => "coasted",
+            Self::None => "none",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Solver {
+    /// The extended Kalman filter
+    Ekf,
+    /// An independent weighted least-squares fix per frame, without a motion model
+    Wls,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NoDetection {
+    /// End the simulation
+    Stop,
+    /// Keep filtering but leave the steps out of the output until a measurement update
+    Gap,
+}
+
+/// A single row of `simulate` output, in meters, serialized with the names of the CSV columns.
+///
+/// The processor does not use it: its text, NMEA and protobuf messages are wire formats the
+/// website and other consumers already parse, and it knows modules by MAC rather than by id.
+#[derive(Clone, Debug, Serialize)]
+pub struct Estimate {
+    /// Timestamp of the input row, or seconds since the start if the input has none.
+    pub time: f64,
+    /// Whether `time` is the timestamp of the input row.
+    pub timestamped: bool,
+    /// Position relative to the ENU reference.
+    pub east: f64,
+    pub north: f64,
+    pub up: f64,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    /// Standard deviations of the east and north position estimates.
+    pub std_east: f64,
+    pub std_north: f64,
+    /// Covariance of the east and north position estimates, rows and columns in that order.
+    pub cov: [[f64; 2]; 2],
+    /// Estimated east and north velocity, `None` with `--solver wls`, which estimates none.
+    pub vel_east: Option<f64>,
+    pub vel_north: Option<f64>,
+    /// Ids of the modules whose measurements passed filtering in this step.
+    pub modules: Vec<i32>,
+    /// Whether a measurement update was applied in this step.
+    pub fix_type: FixType,
+    /// Log-likelihood of the innovation, if a measurement update was applied.
+    pub log_likelihood: Option<f64>,
+    /// Horizontal dilution of precision, if a measurement update was applied.
+    pub hdop: Option<f64>,
+    /// Whether the position was interpolated between measured steps.
+    pub interpolated: bool,
+    /// Whether the drone was considered absent, see `--max-iter-without-detection`.
+    pub absent: bool,
+}
+
+impl Estimate {
+    /// Converts the lengths and velocities from meters, leaving latitude and longitude alone.
+    fn convert(&mut self, units: Units) {
+        for value in [
+            &mut self.east,
+            &mut self.north,
+            &mut self.up,
+            &mut self.alt,
+            &mut self.std_east,
+            &mut self.std_north,
+        ] {
+            *value = units.from_meters(*value);
+        }
+        for value in [&mut self.vel_east, &mut self.vel_north]
+            .into_iter()
+            .flatten()
+        {
+            *value = units.from_meters(*value);
+        }
+        for value in self.cov.as_flattened_mut() {
+            *value = units.from_meters(units.from_meters(*value));
+        }
+    }
+}
+
+/// East and north block of the state covariance `P`.
+fn position_covariance(P: &DMatrix<Real>) -> [[f64; 2]; 2] {
+    [
+        [P[(0, 0)] as f64, P[(0, 1)] as f64],
+        [P[(1, 0)] as f64, P[(1, 1)] as f64],
+    ]
+}
+
+/// Linearly interpolates the positions of unmeasured steps lying between two measured steps.
+///
+/// Returns the number of interpolated estimates.
+fn fill_gaps(results: &mut [Estimate], ref_lle: &Lle<Wgs84>) -> usize {
+    let measured: Vec<usize> = (0..results.len())
+        .filter(|&i| results[i].fix_type == FixType::Measured)
+        .collect();
+    let mut count = 0;
+    for pair in measured.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let (east_a, north_a, up_a) = (results[a].east, results[a].north, results[a].up);
+        let (east_b, north_b, up_b) = (results[b].east, results[b].north, results[b].up);
+        for (k, r) in results[a + 1..b].iter_mut().enumerate() {
+            let t = (k + 1) as f64 / (b - a) as f64;
+            let enu = Enu {
+                east: Meters::new(east_a + t * (east_b - east_a)),
+                north: Meters::new(north_a + t * (north_b - north_a)),
+                up: Meters::new(up_a + t * (up_b - up_a)),
+            };
+            let lle = CoordinateSystem::enu_to_lle(ref_lle, &enu);
+            r.east = enu.east.as_float();
+            r.north = enu.north.as_float();
+            r.up = enu.up.as_float();
+            r.lat = lle.latitude.as_float();
+            r.lon = lle.longitude.as_float();
+            r.alt = lle.elevation.as_float();
+            r.interpolated = true;
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Indices of the points kept by Douglas-Peucker simplification with the given tolerance.
+fn douglas_peucker(points: &[(f64, f64)], tolerance: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut segments = vec![(0, points.len() - 1)];
+    while let Some((a, b)) = segments.pop() {
+        let ((xa, ya), (xb, yb)) = (points[a], points[b]);
+        let len = ((xb - xa).powi(2) + (yb - ya).powi(2)).sqrt();
+        let (mut max_dist, mut max_i) = (0.0, a);
+        for (i, &(x, y)) in points.iter().enumerate().take(b).skip(a + 1) {
+            let dist = if len > 0.0 {
+                ((xb - xa) * (ya - y) - (xa - x) * (yb - ya)).abs() / len
+            } else {
+                ((x - xa).powi(2) + (y - ya).powi(2)).sqrt()
+            };
+            if dist > max_dist {
+                (max_dist, max_i) = (dist, i);
+            }
+        }
+        if max_dist > tolerance {
+            keep[max_i] = true;
+            segments.push((a, max_i));
+            segments.push((max_i, b));
+        }
+    }
+    (0..points.len()).filter(|&i| keep[i]).collect()
+}
+
+#[allow(unused)]
+#[derive(Deserialize)]
+struct ModuleRecord {
+    module: i32,
+    lat: f64,
+    lon: f64,
+    /// Elevation of the module in meters.
+    #[serde(default)]
+    alt: f64,
+    /// Offset of the ranging antenna from the reported position, in meters.
+    #[serde(default)]
+    offset_east: f64,
+    #[serde(default)]
+    offset_north: f64,
+    #[serde(default)]
+    offset_up: f64,
+    /// Measurement standard deviation of this module, the filter default if absent.
+    #[serde(default)]
+    stddev: Option<Real>,
+    /// Longest range accepted from this module, the filter default if absent.
+    #[serde(default)]
+    max_dist: Option<Real>,
+}
+
+/// True drone position of one input row, for `--truth-csv`.
+#[derive(Deserialize)]
+struct TruthRecord {
+    lat: f64,
+    lon: f64,
+}
+
+/// One recording of a `--batch` manifest.
+#[derive(Deserialize)]
+struct BatchRecord {
+    input_dir: String,
+    output_csv: String,
+    modules_csv: String,
+}
+
+/// Distance file of each module, with the module number.
+type DistanceFiles = Vec<(i32, PathBuf)>;
+
+/// Distance files and modules of a simulation, checked before any row is filtered.
+struct Inputs {
+    csvs: DistanceFiles,
+    modules: Vec<ModuleRecord>,
+    /// Recording replayed instead of the distance files, with the MAC address of each module.
+    replay: Option<(PathBuf, Vec<String>)>,
+    /// Origin of the ENU coordinates.
+    ref_lle: Lle<Wgs84>,
+}
+
+/// Runs the filter over the distance files in `args.input_dir` and writes the estimates to
+/// `args.output_csv`, or over every recording of the `args.batch` manifest.
+///
+/// CSV rows are written as the filter produces them, the other formats once the input ends.
+pub fn simulate(args: &LocationSimArgs) -> Result<(), SimError> {
+    if let Some(manifest) = &args.batch {
+        return simulate_batch(Path::new(manifest), args);
+    }
+    let inputs = load_inputs(args)?;
+    if args.dry_run {
+        return check_inputs(&inputs.csvs, args.start_frame, args.end_frame);
+    }
+
+    let columns = match args.output_crs {
+        OutputCrs::Wgs84 => PositionColumns::Wgs84,
+        OutputCrs::Utm => {
+            let zone = utm::zone(inputs.ref_lle.longitude.as_float());
+            log::info!("Writing UTM coordinates in zone {zone}, from the reference longitude");
+            PositionColumns::Utm(zone)
+        }
+        OutputCrs::Enu => PositionColumns::Enu,
+        OutputCrs::Ned => PositionColumns::Ned,
+    };
+    if args.output_format != OutputFormat::Csv {
+        if args.output_crs != OutputCrs::Wgs84 {
+            log::warn!("--output-crs only applies to CSV output, writing WGS84 coordinates");
+        }
+        if args.units != Units::Meters {
+            log::warn!("--units only applies to CSV output, writing meters");
+        }
+    }
+
+    let mut out = create_output(&args.output_csv)?;
+    match args.output_format {
+        OutputFormat::Csv => {
+            let layout = CsvLayout {
+                columns,
+                annotate_modules: args.annotate_modules,
+                interpolate_gaps: args.interpolate_gaps,
+                likelihood: args.likelihood,
+                hdop: args.hdop,
+            };
+            layout.write_header(&mut out)?;
+            run_filter(args, inputs, |mut estimate| {
+                estimate.convert(args.units);
+                Ok(layout.write_row(&mut out, &estimate)?)
+            })?;
+        }
+        OutputFormat::Geojson => write_geojson(&mut out, &collect_estimates(args, inputs)?)?,
+        OutputFormat::Kml => {
+            let results = collect_estimates(args, inputs)?;
+            write_kml(&mut out, &results, results.iter().all(|r| r.timestamped))?
+        }
+        OutputFormat::Gpx => {
+            let results = collect_estimates(args, inputs)?;
+            let timestamped = results.iter().all(|r| r.timestamped);
+            write_gpx(&mut out, &results, timestamped, args.three_d)?
+        }
+        OutputFormat::Jsonl => run_filter(args, inputs, |estimate| {
+            serde_json::to_writer(&mut out, &estimate).map_err(std::io::Error::from)?;
+            Ok(writeln!(out)?)
+        })?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Runs the filter like [`simulate`], passing each estimate to `on_estimate` instead of writing
+/// the output file, so that memory use does not grow with the length of the recording.
+///
+/// Estimates are passed as soon as they are computed, in meters whatever `--units` says. With
+/// `--smooth`, `--interpolate-gaps` or `--decimate`, which need the whole track, they are kept
+/// until the input ends. `--batch` is ignored.
+pub fn for_each_estimate(
+    args: &LocationSimArgs,
+    on_estimate: impl FnMut(Estimate) -> Result<(), SimError>,
+) -> Result<(), SimError> {
+    let inputs = load_inputs(args)?;
+    if args.dry_run {
+        return check_inputs(&inputs.csvs, args.start_frame, args.end_frame);
+    }
+    run_filter(args, inputs, on_estimate)
+}
+
+/// Runs the filter over the whole input, for the output formats written in one go.
+fn collect_estimates(args: &LocationSimArgs, inputs: Inputs) -> Result<Vec<Estimate>, SimError> {
+    let mut results = Vec::new();
+    run_filter(args, inputs, |estimate| {
+        results.push(estimate);
+        Ok(())
+    })?;
+    Ok(results)
+}
+
+/// Pairs the distance files in `args.input_dir` with the modules of `args.modules_csv`, or reads
+/// the modules of the `args.replay` recording.
+fn load_inputs(args: &LocationSimArgs) -> Result<Inputs, SimError> {
+    let (mut csvs, mut modules, mut replay) = match &args.replay {
+        Some(path) => {
+            let (modules, macs) = recorded_modules(Path::new(path))?;
+            (Vec::new(), modules, Some((PathBuf::from(path), macs)))
+        }
+        None => {
+            let (csvs, modules) = load_distance_files(args)?;
+            (csvs, modules, None)
+        }
+    };
+
+    let valid: Vec<bool> = modules
+        .iter()
+        .map(|m| match aggregate::check_position(m.lat, m.lon) {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("Module {}: {err}, ignoring it", m.module);
+                false
+            }
+        })
+        .collect();
+    let mut keep = valid.iter().copied();
+    csvs.retain(|_| keep.next().unwrap());
+    if let Some((_, macs)) = replay.as_mut() {
+        let mut keep = valid.iter().copied();
+        macs.retain(|_| keep.next().unwrap());
+    }
+    let mut keep = valid.into_iter();
+    modules.retain(|_| keep.next().unwrap());
+    if modules.is_empty() {
+        return Err(SimError::NoModules);
+    }
+
+    if let Some(end) = args.end_frame
+        && end <= args.start_frame
+    {
+        return Err(SimError::EmptyFrameRange {
+            start: args.start_frame,
+            end,
+        });
+    }
+
+    let ref_lle = match args.ref_lat.zip(args.ref_lon) {
+        Some((lat, lon)) => {
+            Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0))
+        }
+        None => aggregate::centroid(modules.iter().map(|m| (m.lat, m.lon))).unwrap(),
+    };
+    Ok(Inputs {
+        csvs,
+        modules,
+        replay,
+        ref_lle,
+    })
+}
+
+/// Distance files in `args.input_dir` and the modules of `args.modules_csv` they belong to, in
+/// the same order.
+fn load_distance_files(
+    args: &LocationSimArgs,
+) -> Result<(DistanceFiles, Vec<ModuleRecord>), SimError> {
+    let mut csvs: DistanceFiles = Vec::new();
+    for entry in std::fs::read_dir(&args.input_dir)? {
+        let path = entry?.path();
+        let num = path
+            .to_str()
+            .and_then(|p| DIST_FILE.captures(p))
+            .and_then(|c| c[1].parse().ok())
+            .ok_or_else(|| SimError::NoFileNumber(path.clone()))?;
+        csvs.push((num, path));
+    }
+    csvs.sort_unstable_by_key(|(num, _)| *num);
+
+    let mut modules_reader = csv_reader(Path::new(&args.modules_csv))?;
+
+    let mut modules = Vec::new();
+    for module in modules_reader.deserialize() {
+        let r: ModuleRecord = module?;
+        modules.push(r);
+    }
+
+    if args.match_by_id {
+        let mut by_id: HashMap<i32, ModuleRecord> =
+            modules.into_iter().map(|m| (m.module, m)).collect();
+        modules = Vec::new();
+        csvs.retain(|(id, csv)| match by_id.remove(id) {
+            Some(module) => {
+                modules.push(module);
+                true
+            }
+            None => {
+                log::warn!("No module {id} in the modules CSV, ignoring {}", csv.display());
+                false
+            }
+        });
+    }
+
+    if modules.is_empty() || modules.len() != csvs.len() {
+        return Err(SimError::CountMismatch {
+            modules: modules.len(),
+            files: csvs.len(),
+        });
+    }
+    Ok((csvs, modules))
+}
+
+/// Modules of a recording, numbered from 1 in the order of their MAC addresses, at the position
+/// of their first report, and their MAC addresses in the same order.
+fn recorded_modules(path: &Path) -> Result<(Vec<ModuleRecord>, Vec<String>), SimError> {
+    let mut positions = BTreeMap::new();
+    for report in csv_reader(path)?.deserialize() {
+        let report: Report = report?;
+        positions
+            .entry(report.mac)
+            .or_insert((report.lat, report.lon, report.alt));
+    }
+    let modules = positions
+        .iter()
+        .zip(1..)
+        .map(|((mac, &(lat, lon, alt)), module)| {
+            log::info!("Module {module}: {mac}");
+            ModuleRecord {
+                module,
+                lat,
+                lon,
+                alt,
+                offset_east: 0.0,
+                offset_north: 0.0,
+                offset_up: 0.0,
+                stddev: None,
+                max_dist: None,
+            }
+        })
+        .collect();
+    Ok((modules, positions.into_keys().collect()))
+}
+
+/// The filter loop shared by [`simulate`] and [`for_each_estimate`].
+fn run_filter(
+    args: &LocationSimArgs,
+    inputs: Inputs,
+    mut on_estimate: impl FnMut(Estimate) -> Result<(), SimError>,
+) -> Result<(), SimError> {
+    let LocationSimArgs {
+        input_dir: _,
+        modules_csv: _,
+        output_csv: _,
+        max_dist,
+        dump_matrices,
+        outlier_band,
+        range_smoothing,
+        drone_alt,
+        ref_lat: _,
+        ref_lon: _,
+        annotate_modules: _,
+        singular_retries,
+        interpolate_gaps,
+        strict,
+        gate_meters,
+        processor_mode,
+        likelihood: _,
+        hdop: _,
+        match_by_id: _,
+        decimate,
+        max_iter_without_detection,
+        on_no_detection,
+        three_d,
+        fit_altitude,
+        expected_altitude,
+        expected_altitude_stddev,
+        init_pos_stddev,
+        process_noise,
+        measurement_noise,
+        bearing_noise,
+        solver,
+        motion_model,
+        range_model,
+        range_epsilon,
+        tdoa_reference,
+        chi2_gate,
+        merge_distance,
+        max_speed,
+        two_sensor_factor,
+        max_process_noise_scale,
+        smooth,
+        smooth_window,
+        innovation_csv,
+        covariance_csv,
+        truth_csv,
+        start_frame,
+        end_frame,
+        dry_run: _,
+        dt,
+        realtime,
+        speed,
+        ws_out,
+        output_format: _,
+        output_crs: _,
+        units,
+        batch: _,
+        jobs: _,
+        replay: _,
+        #[cfg(feature = "protobuf")]
+        protobuf,
+    } = args;
+    let Inputs {
+        csvs,
+        modules,
+        replay,
+        ref_lle,
+    } = inputs;
+    let mut dump_matrices = *dump_matrices;
+    if *solver == Solver::Wls {
+        let ekf_only = [
+            ("--smooth", *smooth),
+            ("--dump-matrices", dump_matrices.is_some()),
+            ("--chi2-gate", chi2_gate.is_some()),
+            ("--gate-meters", gate_meters.is_some()),
+            ("--two-sensor-factor", two_sensor_factor.is_some()),
+            ("--max-speed", max_speed.is_some()),
+            ("--max-process-noise-scale", max_process_noise_scale.is_some()),
+        ];
+        for (flag, _) in ekf_only.iter().filter(|(_, set)| *set) {
+            log::warn!("{flag} only applies to the EKF solver, ignoring it");
+        }
+        dump_matrices = None;
+    }
+    if *solver == Solver::Wls && *range_model == RangeModel::Tdoa {
+        log::warn!("--solver wls does not use range differences, there will be no fixes");
+    }
+    let tdoa_reference = match tdoa_reference {
+        Some(id) => modules
+            .iter()
+            .position(|m| m.module == *id)
+            .ok_or(SimError::UnknownModule(*id))?,
+        None => 0,
+    };
+    let smooth = *smooth && *solver == Solver::Ekf;
+
+    // a recording holds module reports, which only the processor's aggregation makes frames of
+    let processor_mode = *processor_mode || replay.is_some();
+    let rows = match replay {
+        Some((path, macs)) => replay_rows(csv_reader(&path)?, macs, *dt),
+        None => {
+            let mut readers = Vec::new();
+            for (_, csv) in &csvs {
+                readers.push(csv_reader(csv)?);
+            }
+            read_rows(readers, csvs.into_iter().map(|(_, path)| path).collect())
+        }
+    };
+
+    let mut sensors: Vec<Sensor> = modules
+        .iter()
+        .map(|m| {
+            let lle =
+                Lle::<Wgs84>::new(Degrees::new(m.lat), Degrees::new(m.lon), Meters::new(m.alt));
+            let enu = CoordinateSystem::lle_to_enu(&ref_lle, &lle);
+            let enu = Enu {
+                east: Meters::new(enu.east.as_float() + m.offset_east),
+                north: Meters::new(enu.north.as_float() + m.offset_north),
+                up: Meters::new(enu.up.as_float() + m.offset_up),
+            };
+            Sensor {
+                enu,
+                dist: 0.0,
+                stddev: m.stddev,
+                max_dist: m.max_dist,
+                bearing: None,
+            }
+        })
+        .collect();
+
+    aggregate::check_layout(&sensors);
+
+    let mut truth = Vec::new();
+    if let Some(path) = truth_csv {
+        for record in csv_reader(Path::new(path))?.deserialize() {
+            let TruthRecord { lat, lon } = record?;
+            let lle = Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0));
+            let enu = CoordinateSystem::lle_to_enu(&ref_lle, &lle);
+            truth.push((enu.east.as_float() as Real, enu.north.as_float() as Real));
+        }
+    }
+    let mut nees = Vec::new();
+
+    // smoothing, interpolation and decimation need the whole track
+    let buffered = smooth || *interpolate_gaps || decimate.is_some();
+    let mut results = Vec::new();
+    let mut left_out = 0;
+    let config = EkfConfig {
+        motion_model: *motion_model,
+        range_model: *range_model,
+        range_epsilon: *range_epsilon,
+        tdoa_reference,
+        init_pos_stddev: *init_pos_stddev,
+        process_noise_stddev: *process_noise,
+        measurement_stddev: *measurement_noise,
+        bearing_stddev: bearing_noise.to_radians(),
+        max_dist: *max_dist,
+        chi2_gate: *chi2_gate,
+        max_speed: *max_speed,
+        two_sensor_noise_factor: *two_sensor_factor,
+        max_process_noise_scale: *max_process_noise_scale,
+        merge_distance: *merge_distance,
+        expected_altitude: *expected_altitude,
+        expected_altitude_stddev: *expected_altitude_stddev,
+        ..Default::default()
+    };
+    let mut ekf = if *three_d {
+        Ekf::new_3d(0.0, 0.0, drone_alt.unwrap_or(0.0), config)
+    } else {
+        Ekf::with_config(0.0, 0.0, config)
+    };
+    ekf.drone_alt = *drone_alt;
+    ekf.singular_retries = *singular_retries;
+    ekf.strict = *strict;
+    ekf.gate_meters = *gate_meters;
+    let dt = *dt;
+
+    #[cfg(feature = "protobuf")]
+    let mut protobuf = protobuf
+        .as_ref()
+        .map(|path| create_output(path))
+        .transpose()?;
+
+    let mut innovations = innovation_csv
+        .as_ref()
+        .map(|path| create_output(path))
+        .transpose()?;
+    if let Some(out) = innovations.as_mut() {
+        let unit = if *range_model == RangeModel::SquaredRange {
+            "m2"
+        } else {
+            "m"
+        };
+        writeln!(out, "time,ranges,mean_abs_{unit},max_abs_{unit},modules,innovations")?;
+    }
+
+    let mut covariances = covariance_csv
+        .as_ref()
+        .map(|path| create_output(path))
+        .transpose()?;
+    if let Some(out) = covariances.as_mut() {
+        let names = ekf.state_names();
+        write!(out, "frame")?;
+        for (i, row) in names.iter().enumerate() {
+            for column in &names[i..] {
+                write!(out, ",{row}:{column}")?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    let mut socket = match ws_out {
+        Some(ws_out) => {
+            let (socket, _response) = tungstenite::connect(format!("ws://{ws_out}"))?;
+            log::info!("WebSocket connected to {ws_out}");
+            Some(socket)
+        }
+        None => None,
+    };
+
+    let start = Instant::now();
+    let mut first_time = None;
+    let mut aggregator = processor_mode.then_some(Aggregator {
+        ref_lle: Some(ref_lle),
+        two_sensor: two_sensor_factor.is_some(),
+    });
+    let mut reports: HashMap<i32, Module> = HashMap::new();
+    let mut last_solve = 0.0;
+    let mut last_timestamp: Option<f64> = None;
+
+    let mut history = Vec::new();
+    let mut moving_average = (*smooth_window > 1).then(|| MovingAverage::new(*smooth_window));
+    // latest altitude from --fit-altitude, kept through steps without an update
+    let mut altitude = 0.0;
+    let mut counter = 0;
+    let mut without_detection = 0;
+    let mut rejections = vec![0usize; modules.len()];
+    // smoothed distance of each module, kept through rows where it is missing
+    let mut range_averages: Vec<Option<f64>> = vec![None; modules.len()];
+
+    loop {
+        let Ok(row) = rows.recv() else {
+            // the input ended before the requested range
+            if let Some(frame) = frame_out_of_range(*start_frame, *end_frame, counter) {
+                return Err(SimError::FrameOutOfRange {
+                    frame,
+                    frames: counter,
+                });
+            }
+            log::info!("Done: {counter}");
+            break;
+        };
+        if end_frame.is_some_and(|end| counter >= end) {
+            log::info!("Reached frame {counter}, stopping");
+            break;
+        }
+        let Row {
+            dists,
+            bearings,
+            timestamp,
+        } = row?;
+        if counter < *start_frame {
+            counter += 1;
+            continue;
+        }
+        for ((sensor, dist), bearing) in sensors.iter_mut().zip(dists).zip(bearings) {
+            sensor.dist = dist as Real;
+            sensor.bearing = bearing.map(|b| b.to_radians() as Real);
+        }
+
+        if let Some(band) = outlier_band {
+            let valid: Vec<usize> = (0..sensors.len())
+                .filter(|&i| sensors[i].dist > 0.0)
+                .collect();
+            let dists: Vec<f64> = valid.iter().map(|&i| sensors[i].dist as f64).collect();
+            let rejected: Vec<usize> = outliers::median_outliers(&dists, *band)
+                .into_iter()
+                .map(|i| valid[i])
+                .collect();
+            if !rejected.is_empty() {
+                let ids: Vec<i32> = rejected.iter().map(|&i| modules[i].module).collect();
+                log::info!("Step {counter}: rejected {} outlier(s), modules {ids:?}", ids.len());
+            }
+            for i in rejected {
+                sensors[i].dist = 0.0;
+                rejections[i] += 1;
+            }
+        }
+
+        if *range_smoothing < 1.0 {
+            for (sensor, average) in sensors.iter_mut().zip(&mut range_averages) {
+                if sensor.dist > 0.0 {
+                    let smoothed = smoothing::ema(*average, sensor.dist as f64, *range_smoothing);
+                    *average = Some(smoothed);
+                    sensor.dist = smoothed as Real;
+                }
+            }
+        }
+
+        let time = timestamp.unwrap_or(counter as f64 * dt);
+        let (ids, step_dt, step_sensors) = match aggregator.as_mut() {
+            Some(aggregator) => {
+                let now = start + Duration::from_secs_f64(time);
+                for (m, sensor) in modules.iter().zip(&sensors) {
+                    if sensor.dist > 0.0 {
+                        let report = Module {
+                            lat: m.lat,
+                            lon: m.lon,
+                            alt: m.alt,
+                            drone: true,
+                            dist: sensor.dist as f64,
+                            updated: now,
+                            track: None,
+                        };
+                        reports.insert(m.module, report);
+                    }
+                }
+                aggregator.retain(&mut reports, now);
+                match aggregator.frame(&reports) {
+                    Frame::Ready { keys, sensors } => {
+                        let step_dt = ((time - last_solve) as Real).clamp(ekf::MIN_DT, ekf::MAX_DT);
+                        last_solve = time;
+                        (keys, step_dt, sensors)
+                    }
+                    Frame::NoDetection | Frame::NotEnough => {
+                        counter += 1;
+                        without_detection += 1;
+                        if *on_no_detection == NoDetection::Stop
+                            && max_iter_without_detection
+                                .is_some_and(|max| without_detection >= max)
+                        {
+                            log::info!("No detection for {without_detection} steps, stopping");
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => {
+                let step_dt = match (timestamp, last_timestamp) {
+                    (Some(t), Some(last)) => ((t - last) as Real).clamp(ekf::MIN_DT, ekf::MAX_DT),
+                    _ => dt as Real,
+                };
+                (modules.iter().map(|m| m.module).collect(), step_dt, sensors.clone())
+            }
+        };
+        last_timestamp = timestamp;
+
+        // the scale this step predicts with, before the update adapts it
+        let process_noise_scale = ekf.process_noise_scale;
+        let predicted = match solver {
+            Solver::Ekf => {
+                let (x_pred, P_pred) = ekf.predict(step_dt);
+                let predicted = smooth.then(|| (x_pred.clone(), P_pred.clone()));
+                ekf.update(x_pred, P_pred, &step_sensors);
+                predicted
+            }
+            Solver::Wls => {
+                ekf.solve_wls(&step_sensors);
+                None
+            }
+        };
+        if ekf.last_update.H.is_some() {
+            without_detection = 0;
+        } else {
+            without_detection += 1;
+        }
+        let absent = max_iter_without_detection.is_some_and(|max| without_detection >= max);
+        if absent && *on_no_detection == NoDetection::Stop {
+            log::info!("No detection for {without_detection} steps, stopping");
+            break;
+        }
+        if let Some((x_pred, P_pred)) = predicted {
+            history.push(FilterStep {
+                F: (ekf.F)(step_dt),
+                x_pred,
+                P_pred,
+                x_est: ekf.x_est.clone(),
+                P_est: ekf.P_est.clone(),
+            });
+        }
+
+        if let (Some(out), Some(y)) = (innovations.as_mut(), &ekf.last_update.innovation) {
+            // the ranges come first, followed by the bearings and the expected altitude
+            let update = &ekf.last_update;
+            let count = y.len() - update.bearings.len() - update.altitude as usize;
+            let ranges: Vec<(i32, Real)> = (0..count)
+                .filter_map(|row| update.rows[row].map(|i| (ids[i], y[row])))
+                .collect();
+            if !ranges.is_empty() {
+                let abs = ranges.iter().map(|(_, y)| y.abs());
+                let mean = abs.clone().sum::<Real>() / ranges.len() as Real;
+                let max = abs.fold(0.0, Real::max);
+                let modules: Vec<String> = ranges.iter().map(|(id, _)| id.to_string()).collect();
+                let values: Vec<String> = ranges.iter().map(|(_, y)| y.to_string()).collect();
+                writeln!(
+                    out,
+                    "{time},{},{mean},{max},\"{}\",\"{}\"",
+                    ranges.len(),
+                    modules.join(","),
+                    values.join(",")
+                )?;
+            }
+        }
+
+        if let Some(out) = covariances.as_mut() {
+            let P = &ekf.P_est;
+            write!(out, "{counter}")?;
+            for i in 0..P.nrows() {
+                for j in i..P.ncols() {
+                    write!(out, ",{}", P[(i, j)])?;
+                }
+            }
+            writeln!(out)?;
+        }
+
+        if !ekf.last_update.gated.is_empty() {
+            let ids: Vec<i32> = ekf.last_update.gated.iter().map(|&i| ids[i]).collect();
+            log::info!("Step {counter}: gated modules {ids:?}");
+        }
+
+        if let (Some(dump), Some(H), Some(R)) =
+            (dump_matrices, &ekf.last_update.H, &ekf.last_update.R)
+        {
+            println!("Step {counter}, dt = {step_dt}");
+            println!("F ={:.6}", (ekf.F)(step_dt));
+            println!("Q ={:.6e}", (ekf.Q)(step_dt) * process_noise_scale);
+            println!("H ={H:.6}");
+            println!("R ={R:.6}");
+            if dump == DumpMatrices::Exit {
+                return Ok(());
+            }
+            dump_matrices = None;
+        }
+
+        if *fit_altitude && let Some(fitted) = ekf.fitted_altitude(&step_sensors) {
+            altitude = fitted;
+        }
+        let up = if *fit_altitude {
+            altitude
+        } else {
+            ekf.altitude()
+        };
+        let (east, north) = ekf.east_north();
+        let enu = Enu {
+            east: Meters::new(east as f64),
+            north: Meters::new(north as f64),
+            up: Meters::new(up as f64),
+        };
+        let enu = match moving_average.as_mut() {
+            Some(average) => average.push(&enu),
+            None => enu,
+        };
+
+        let lle = CoordinateSystem::enu_to_lle(&ref_lle, &enu);
+
+        #[cfg(feature = "protobuf")]
+        if let Some(protobuf) = protobuf.as_mut() {
+            let point = proto::TrackPoint::new(time, &lle, &ekf);
+            protobuf.write_all(&point.encode_length_delimited_to_vec())?;
+        }
+
+        if *realtime {
+            let elapsed = time - *first_time.get_or_insert(time);
+            let due = start + Duration::from_secs_f64(elapsed.max(0.0) / speed);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        if let Some(socket) = socket.as_mut() {
+            let text = processor::format_fix(&lle, &ekf, false, false, *units);
+            socket.send(tungstenite::Message::Text(text.into()))?;
+        }
+
+        if let Some(&(east, north)) = truth.get(counter)
+            && let Some(value) = ekf.position_nees(east, north)
+        {
+            nees.push(value);
+        }
+
+        let (std_east, std_north) = ekf.position_stddev();
+        let (vel_east, vel_north) = match solver {
+            Solver::Ekf => {
+                let (east, north) = ekf.velocity();
+                (Some(east as f64), Some(north as f64))
+            }
+            Solver::Wls => (None, None),
+        };
+        let estimate = Estimate {
+            time,
+            timestamped: timestamp.is_some(),
+            east: enu.east.as_float(),
+            north: enu.north.as_float(),
+            up: enu.up.as_float(),
+            lat: lle.latitude.as_float(),
+            lon: lle.longitude.as_float(),
+            alt: lle.elevation.as_float(),
+            std_east: std_east as f64,
+            std_north: std_north as f64,
+            cov: position_covariance(&ekf.P_est),
+            vel_east,
+            vel_north,
+            modules: {
+                let mut used: Vec<usize> = ekf
+                    .last_update
+                    .used
+                    .iter()
+                    .chain(&ekf.last_update.merged)
+                    .copied()
+                    .collect();
+                used.sort_unstable();
+                used.into_iter().map(|i| ids[i]).collect()
+            },
+            fix_type: FixType::of(&ekf.last_update),
+            log_likelihood: ekf.last_update.log_likelihood.map(|l| l as f64),
+            hdop: ekf.hdop().map(|h| h as f64),
+            interpolated: false,
+            absent,
+        };
+        counter += 1;
+        if buffered {
+            results.push(estimate);
+        } else if absent {
+            left_out += 1;
+        } else {
+            on_estimate(estimate)?;
+        }
+    }
+
+    if *singular_retries > 0 {
+        log::info!(
+            "Dropped {} sensor(s) to recover from a singular innovation covariance",
+            ekf.singular_retry_count
+        );
+    }
+
+    if truth_csv.is_some() {
+        if truth.len() < counter {
+            log::warn!("The truth CSV has {} rows for {counter} input rows", truth.len());
+        }
+        let inside = nees
+            .iter()
+            .filter(|&&value| (NEES_BOUNDS.0..=NEES_BOUNDS.1).contains(&value))
+            .count();
+        log::info!(
+            "NEES: mean {:.2} (2 if consistent) over {} steps, {:.1}% inside the 95% bounds",
+            nees.iter().sum::<Real>() / nees.len().max(1) as Real,
+            nees.len(),
+            100.0 * inside as f64 / nees.len().max(1) as f64
+        );
+    }
+
+    if outlier_band.is_some() {
+        let steps = counter.saturating_sub(*start_frame);
+        for (module, count) in modules.iter().zip(rejections) {
+            log::info!(
+                "Module {}: rejected as outlier in {count}/{steps} steps ({:.1}%)",
+                module.module,
+                100.0 * count as f64 / steps.max(1) as f64
+            );
+        }
+    }
+
+    if smooth {
+        let dims = ekf.dims();
+        for (r, (x, P)) in results.iter_mut().zip(ekf::rts_smooth(&history)) {
+            let enu = Enu {
+                east: Meters::new(x[0] as f64),
+                north: Meters::new(x[1] as f64),
+                up: Meters::new(if dims == 3 { x[2] as f64 } else { r.up }),
+            };
+            let lle = CoordinateSystem::enu_to_lle(&ref_lle, &enu);
+            r.east = enu.east.as_float();
+            r.north = enu.north.as_float();
+            r.up = enu.up.as_float();
+            r.lat = lle.latitude.as_float();
+            r.lon = lle.longitude.as_float();
+            r.alt = lle.elevation.as_float();
+            r.std_east = P[(0, 0)].sqrt() as f64;
+            r.std_north = P[(1, 1)].sqrt() as f64;
+            r.cov = position_covariance(&P);
+            r.vel_east = Some(x[dims] as f64);
+            r.vel_north = Some(x[dims + 1] as f64);
+        }
+    }
+
+    if max_iter_without_detection.is_some() && *on_no_detection == NoDetection::Gap {
+        let total = results.len();
+        results.retain(|r| !r.absent);
+        left_out += total - results.len();
+        log::info!("Left out {left_out} estimates while the drone was absent");
+    }
+
+    if *interpolate_gaps {
+        let count = fill_gaps(&mut results, &ref_lle);
+        log::info!("Interpolated {count}/{} estimates", results.len());
+    }
+
+    if let Some(tolerance) = decimate {
+        let points: Vec<(f64, f64)> = results.iter().map(|r| (r.east, r.north)).collect();
+        let kept = douglas_peucker(&points, *tolerance);
+        let total = results.len();
+        let mut kept = kept.into_iter().peekable();
+        let mut i = 0;
+        results.retain(|_| {
+            let keep = kept.next_if_eq(&i).is_some();
+            i += 1;
+            keep
+        });
+        log::info!(
+            "Decimated {total} estimates to {} (compression ratio {:.2})",
+            results.len(),
+            total as f64 / results.len().max(1) as f64
+        );
+    }
+
+    if let Some(mut out) = innovations {
+        out.flush()?;
+    }
+    if let Some(mut out) = covariances {
+        out.flush()?;
+    }
+
+    for estimate in results {
+        on_estimate(estimate)?;
+    }
+    Ok(())
+}
+
+/// The `--start-frame` or `--end-frame` lying past the end of an input of `frames` rows.
+fn frame_out_of_range(
+    start_frame: usize,
+    end_frame: Option<usize>,
+    frames: usize,
+) -> Option<usize> {
+    end_frame
+        .filter(|&end| end > frames)
+        .or((start_frame > 0 && start_frame >= frames).then_some(start_frame))
+}
+
+/// Reads every distance file through for `--dry-run`, checking that it parses as CSV and that
+/// its first distance is a number, and prints the row counts.
+fn check_inputs(
+    csvs: &[(i32, PathBuf)],
+    start_frame: usize,
+    end_frame: Option<usize>,
+) -> Result<(), SimError> {
+    let mut frames = 0;
+    for (id, path) in csvs {
+        let mut rows = 0;
+        for record in csv_reader(path)?.into_records() {
+            let record = record?;
+            if rows == 0
+                && let Some(dist) = record.get(0).filter(|d| !d.is_empty())
+                && dist.parse::<f64>().is_err()
+            {
+                let line = record.position().map_or(0, |p| p.line());
+                log::warn!("{}:{line}: invalid number {dist:?}", path.display());
+            }
+            rows += 1;
+        }
+        if rows == 0 {
+            log::warn!("{} has no rows", path.display());
+        }
+        println!("Module {id}: {} with {rows} rows", path.display());
+        frames = frames.max(rows);
+    }
+    println!("{} modules matched to distance files, {frames} frames", csvs.len());
+    match frame_out_of_range(start_frame, end_frame, frames) {
+        Some(frame) => Err(SimError::FrameOutOfRange { frame, frames }),
+        None => Ok(()),
+    }
+}
+
+/// Simulates the recordings of a `--batch` manifest, `args.jobs` at a time.
+///
+/// A failed recording does not stop the others, the failures are logged once all have run.
+fn simulate_batch(manifest: &Path, args: &LocationSimArgs) -> Result<(), SimError> {
+    let records = csv_reader(manifest)?
+        .deserialize()
+        .collect::<Result<Vec<BatchRecord>, _>>()?;
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for job in 0..args.jobs.min(records.len()) {
+            let worker = || {
+                while let Some(record) = records.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    log::info!("Simulating {} into {}", record.input_dir, record.output_csv);
+                    let recording = LocationSimArgs {
+                        input_dir: record.input_dir.clone(),
+                        modules_csv: record.modules_csv.clone(),
+                        output_csv: record.output_csv.clone(),
+                        batch: None,
+                        ..args.clone()
+                    };
+                    if let Err(err) = simulate(&recording) {
+                        failures.lock().unwrap().push((record, err));
+                    }
+                }
+            };
+            std::thread::Builder::new()
+                .name(format!("batch-{job}"))
+                .spawn_scoped(scope, worker)
+                .expect("Failed to spawn batch thread");
+        }
+    });
+
+    let failures = failures.into_inner().unwrap();
+    for (record, err) in &failures {
+        log::error!("Simulation of {} failed: {err}", record.input_dir);
+    }
+    log::info!(
+        "Batch done: {} of {} recordings simulated",
+        records.len() - failures.len(),
+        records.len()
+    );
+    match failures.len() {
+        0 => Ok(()),
+        failed => Err(SimError::BatchFailed {
+            failed,
+            total: records.len(),
+        }),
+    }
+}
+
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        Ok(jobs) => Ok(jobs),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+pub(crate) fn parse_positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        Ok(_) => Err("must be a positive number".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// One row of every distance file.
+struct Row {
+    /// Distance of each module, 0 if its file has ended or the distance is empty.
+    dists: Vec<f64>,
+    /// Bearing of each module in degrees clockwise from north, from an optional `bearing`
+    /// column.
+    bearings: Vec<Option<f64>>,
+    /// Latest timestamp in the row, if any file has a timestamp column.
+    timestamp: Option<f64>,
+}
+
+/// Reads the distance files in lockstep on a separate thread, so that reading and parsing them
+/// overlaps with filtering.
+///
+/// The channel closes after the row in which the last file ends, or after the first error.
+fn read_rows(
+    mut readers: Vec<csv::Reader<Box<dyn Read + Send>>>,
+    paths: Vec<PathBuf>,
+) -> Receiver<Result<Row, SimError>> {
+    let (tx, rx) = sync_channel(ROW_BUFFER);
+    let bearing_columns: Vec<Option<usize>> = readers
+        .iter_mut()
+        .map(|r| {
+            let headers = r.headers().ok()?;
+            headers.iter().position(|h| h == "bearing")
+        })
+        .collect();
+    // each row holds a distance, optionally a timestamp in seconds and, in a column named
+    // `bearing`, optionally a bearing
+    let mut records: Vec<_> = readers.into_iter().map(|r| r.into_records()).collect();
+    spawn(move || {
+        // an invalid number is treated like an empty field, a single bad cell in a long field
+        // recording should not end the run
+        let parse = |i: usize, record: &csv::StringRecord, value: &str| {
+            let parsed = value.parse::<f64>().ok();
+            if parsed.is_none() {
+                let line = record.position().map_or(0, |p| p.line());
+                log::warn!("{}:{line}: ignoring invalid number {value:?}", paths[i].display());
+            }
+            parsed
+        };
+        let read_row = |records: &mut Vec<csv::StringRecordsIntoIter<_>>| {
+            let mut done = true;
+            let mut row = Row {
+                dists: vec![0.0; records.len()],
+                bearings: vec![None; records.len()],
+                timestamp: None,
+            };
+            for (i, record) in records.iter_mut().enumerate() {
+                let Some(record) = record.next() else {
+                    continue;
+                };
+                done = false;
+                let record = record?;
+                if !record[0].is_empty() {
+                    row.dists[i] = parse(i, &record, &record[0]).unwrap_or(0.0);
+                }
+                let bearing = bearing_columns[i].and_then(|c| record.get(c));
+                if let Some(bearing) = bearing.filter(|b| !b.is_empty()) {
+                    row.bearings[i] = parse(i, &record, bearing);
+                }
+                // the second column is the timestamp, unless it is the bearing
+                let timestamp = record.get(1).filter(|_| bearing_columns[i] != Some(1));
+                if let Some(t) = timestamp.filter(|t| !t.is_empty())
+                    && let Some(t) = parse(i, &record, t)
+                {
+                    row.timestamp = Some(row.timestamp.map_or(t, |max: f64| max.max(t)));
+                }
+            }
+            Ok((!done).then_some(row))
+        };
+        loop {
+            let row = match read_row(&mut records) {
+                Ok(Some(row)) => Ok(row),
+                Ok(None) => break,
+                Err(err) => Err(err),
+            };
+            let failed = row.is_err();
+            // the receiver is gone if the simulation stopped early
+            if tx.send(row).is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Reads a recording of the processor on a separate thread as rows `dt` seconds long,
+/// timestamped at their end, like the frames the processor computes every read period.
+///
+/// Each row holds the distance of the latest report of each module within it, with `macs` giving
+/// the MAC address of each module. Reports without a drone are left out like empty cells of a
+/// distance file, as are modules missing from `macs`. Rows without a report are kept, the
+/// processor computes a frame from the retained reports all the same.
+fn replay_rows(
+    reader: csv::Reader<Box<dyn Read + Send>>,
+    macs: Vec<String>,
+    dt: f64,
+) -> Receiver<Result<Row, SimError>> {
+    let (tx, rx) = sync_channel(ROW_BUFFER);
+    spawn(move || {
+        let index: HashMap<String, usize> = macs.into_iter().zip(0..).collect();
+        let empty = || Row {
+            dists: vec![0.0; index.len()],
+            bearings: vec![None; index.len()],
+            timestamp: None,
+        };
+        let mut row = empty();
+        let mut end = None;
+        for report in reader.into_deserialize::<Report>() {
+            let report = match report {
+                Ok(report) => report,
+                Err(err) => {
+                    let _ = tx.send(Err(err.into()));
+                    return;
+                }
+            };
+            let Some(&i) = index.get(&report.mac) else {
+                continue;
+            };
+            let row_end = end.get_or_insert(report.time + dt);
+            while report.time >= *row_end {
+                row.timestamp = Some(*row_end);
+                // the receiver is gone if the simulation stopped early
+                if tx.send(Ok(std::mem::replace(&mut row, empty()))).is_err() {
+                    return;
+                }
+                *row_end += dt;
+            }
+            row.dists[i] = if report.drone { report.dist } else { 0.0 };
+        }
+        if let Some(end) = end {
+            row.timestamp = Some(end);
+            let _ = tx.send(Ok(row));
+        }
+    });
+    rx
+}
+
+/// Opens a CSV file for reading, decompressing it if its name ends with `.gz`.
+fn csv_reader(path: &Path) -> std::io::Result<csv::Reader<Box<dyn Read + Send>>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read + Send> = if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    };
+    Ok(csv::Reader::from_reader(reader))
+}
+
+/// Creates `path` for writing along with its parent directories.
+fn create_output(path: &str) -> std::io::Result<BufWriter<File>> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+/// Columns of the CSV output.
+struct CsvLayout {
+    columns: PositionColumns,
+    annotate_modules: bool,
+    interpolate_gaps: bool,
+    likelihood: bool,
+    hdop: bool,
+}
+
+impl CsvLayout {
+    fn write_header(&self, csv: &mut impl Write) -> std::io::Result<()> {
+        match self.columns {
+            PositionColumns::Wgs84 => write!(csv, "time,lat,lon,alt")?,
+            PositionColumns::Utm(zone) => {
+                writeln!(csv, "# UTM zone {zone}, autodetected from the reference longitude")?;
+                write!(csv, "time,easting,northing,zone,alt")?
+            }
+            PositionColumns::Enu => write!(csv, "time,east,north,up")?,
+            PositionColumns::Ned => write!(csv, "time,north,east,down")?,
+        }
+        write!(csv, ",std_east,std_north,vel_east,vel_north")?;
+        if self.annotate_modules {
+            write!(csv, ",modules")?;
+        }
+        if self.interpolate_gaps {
+            write!(csv, ",interpolated")?;
+        }
+        if self.likelihood {
+            write!(csv, ",log_likelihood")?;
+        }
+        if self.hdop {
+            write!(csv, ",hdop")?;
+        }
+        writeln!(csv, ",fix_type")
+    }
+
+    fn write_row(&self, csv: &mut impl Write, r: &Estimate) -> std::io::Result<()> {
+        match self.columns {
+            PositionColumns::Wgs84 => write!(csv, "{},{},{},{}", r.time, r.lat, r.lon, r.alt)?,
+            PositionColumns::Utm(zone) => {
+                let utm = utm::from_lat_lon(r.lat, r.lon, zone);
+                let hemisphere = if utm.north { 'N' } else { 'S' };
+                write!(
+                    csv,
+                    "{},{},{},{}{hemisphere},{}",
+                    r.time, utm.easting, utm.northing, utm.zone, r.alt
+                )?
+            }
+            PositionColumns::Enu => write!(csv, "{},{},{},{}", r.time, r.east, r.north, r.up)?,
+            PositionColumns::Ned => {
+                write!(csv, "{},{},{},{}", r.time, r.north, r.east, 0.0 - r.up)?
+            }
+        }
+        write!(csv, ",{},{}", r.std_east, r.std_north)?;
+        match r.vel_east.zip(r.vel_north) {
+            Some((east, north)) => write!(csv, ",{east},{north}")?,
+            None => write!(csv, ",,")?,
+        }
+        if self.annotate_modules {
+            let ids: Vec<String> = r.modules.iter().map(|id| id.to_string()).collect();
+            write!(csv, ",\"{}\"", ids.join(","))?;
+        }
+        if self.interpolate_gaps {
+            write!(csv, ",{}", r.interpolated as u8)?;
+        }
+        if self.likelihood {
+            match r.log_likelihood {
+                Some(l) => write!(csv, ",{l}")?,
+                None => write!(csv, ",")?,
+            }
+        }
+        if self.hdop {
+            match r.hdop {
+                Some(h) => write!(csv, ",{h}")?,
+                None => write!(csv, ",")?,
+            }
+        }
+        writeln!(csv, ",{}", r.fix_type.as_str())
+    }
+}
+
+fn write_geojson(out: &mut impl Write, results: &[Estimate]) -> std::io::Result<()> {
+    let coordinates: Vec<String> = results
+        .iter()
+        .map(|r| format!("[{},{},{}]", r.lon, r.lat, r.alt))
+        .collect();
+    writeln!(out, r#"{{"type":"FeatureCollection","features":["#)?;
+    write!(
+        out,
+        r#"{{"type":"Feature","geometry":{{"type":"LineString","coordinates":[{}]}},"properties":{{}}}}"#,
+        coordinates.join(",")
+    )?;
+    for (r, point) in results.iter().zip(&coordinates) {
+        writeln!(out, ",")?;
+        write!(
+            out,
+            r#"{{"type":"Feature","geometry":{{"type":"Point","coordinates":{point}}},"properties":{{"std_east":{},"std_north":{},"vel_east":{},"vel_north":{},"hdop":{}}}}}"#,
+            r.std_east,
+            r.std_north,
+            r.vel_east.map_or("null".to_owned(), |v| v.to_string()),
+            r.vel_north.map_or("null".to_owned(), |v| v.to_string()),
+            r.hdop.map_or("null".to_owned(), |h| h.to_string())
+        )?;
+    }
+    writeln!(out, "\n]}}")
+}
+
+/// Writes the track with the altitude above the modules, which is 0 unless --three-d is set.
+fn write_kml(out: &mut impl Write, results: &[Estimate], timestamped: bool) -> std::io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">"#
+    )?;
+    writeln!(out, "<Placemark>")?;
+    writeln!(out, "<name>Drone track</name>")?;
+    if timestamped && !results.is_empty() {
+        writeln!(out, "<gx:Track>")?;
+        writeln!(out, "<altitudeMode>relativeToGround</altitudeMode>")?;
+        for r in results {
+            writeln!(out, "<when>{}</when>", format_utc(r.time))?;
+        }
+        for r in results {
+            writeln!(out, "<gx:coord>{} {} {}</gx:coord>", r.lon, r.lat, r.up)?;
+        }
+        writeln!(out, "</gx:Track>")?;
+    } else {
+        writeln!(out, "<LineString>")?;
+        writeln!(out, "<altitudeMode>relativeToGround</altitudeMode>")?;
+        writeln!(out, "<coordinates>")?;
+        for r in results {
+            writeln!(out, "{},{},{}", r.lon, r.lat, r.up)?;
+        }
+        writeln!(out, "</coordinates>")?;
+        writeln!(out, "</LineString>")?;
+    }
+    writeln!(out, "</Placemark>")?;
+    writeln!(out, "</kml>")
+}
+
+fn write_gpx(
+    out: &mut impl Write,
+    results: &[Estimate],
+    timestamped: bool,
+    three_d: bool,
+) -> std::io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<gpx version="1.1" creator="drone_processor" xmlns="http://www.topografix.com/GPX/1/1">"#
+    )?;
+    writeln!(out, "<trk>")?;
+    writeln!(out, "<name>Drone track</name>")?;
+    writeln!(out, "<trkseg>")?;
+    for r in results {
+        write!(out, r#"<trkpt lat="{}" lon="{}">"#, r.lat, r.lon)?;
+        if three_d {
+            write!(out, "<ele>{}</ele>", r.alt)?;
+        }
+        if timestamped {
+            write!(out, "<time>{}</time>", format_utc(r.time))?;
+        }
+        writeln!(out, "</trkpt>")?;
+    }
+    writeln!(out, "</trkseg>")?;
+    writeln!(out, "</trk>")?;
+    writeln!(out, "</gpx>")
+}
+
+/// Formats seconds since the UNIX epoch as an ISO 8601 UTC date and time.
+fn format_utc(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as i64;
+    let (days, ms_of_day) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    // civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        ms_of_day / 3_600_000,
+        ms_of_day / 60_000 % 60,
+        ms_of_day / 1000 % 60,
+        ms_of_day % 1000
+    )
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct AnglesRecord {
+    dist_h: f64,
+    angle_h: f64,
+    dist_v: f64,
+    angle_v: f64,
+    dist_real: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnglesSensor {
+    pub enu: Enu,
+    pub data: AnglesRecord,
+}
+
+pub fn simulate_i2s<P: AsRef<Path>>(
+    input_dir: P,
+    modules_csv: P,
+    output_csv: P,
+    max_dist: Option<Real>,
+) {
+    let re_csv = Regex::new(r".*\D(\d+)\.csv$").unwrap();
+
+    let mut csvs: Vec<PathBuf> = std::fs::read_dir(input_dir)
+        .unwrap()
+        .map(|d| d.unwrap().path())
+        .collect();
+    csvs.sort_unstable_by(|a, b| {
+        let a_num: i32 = re_csv.captures(a.to_str().unwrap()).unwrap()[1]
+            .parse()
+            .unwrap();
+        let b_num: i32 = re_csv.captures(b.to_str().unwrap()).unwrap()[1]
+            .parse()
+            .unwrap();
+        a_num.cmp(&b_num)
+    });
+
+    let mut modules_csv = csv::Reader::from_path(modules_csv).unwrap();
+
+    let mut modules = Vec::new();
+    for module in modules_csv.deserialize() {
+        let r: ModuleRecord = module.unwrap();
+        modules.push(r);
+    }
+
+    assert_eq!(modules.len(), csvs.len());
+
+    let mut readers = Vec::new();
+    let mut desers = Vec::new();
+    for csv in csvs {
+        let reader = csv::Reader::from_path(csv).unwrap();
+        readers.push(reader);
+    }
+    for reader in readers.iter_mut() {
+        desers.push(reader.deserialize::<AnglesRecord>());
+    }
+
+    let ref_lle = Lle::<Wgs84>::new(
+        Degrees::new(modules[0].lat),
+        Degrees::new(modules[0].lon),
+        Meters::new(0.0),
+    );
+    let mut sensors: Vec<AnglesSensor> = modules
+        .iter()
+        .map(|m| {
+            let lle = Lle::<Wgs84>::new(Degrees::new(m.lat), Degrees::new(m.lon), Meters::new(0.0));
+            let enu = CoordinateSystem::lle_to_enu(&ref_lle, &lle);
+            AnglesSensor {
+                enu,
+                data: AnglesRecord::default(),
+            }
+        })
+        .collect();
+
+    let mut results = Vec::new();
+
+    let mut counter = 0;
+
+    loop {
+        let records = desers.iter_mut().map(|d| d.next());
+
+        let mut done = false;
+        for (sensor, dist) in sensors.iter_mut().zip(records) {
+            if let Some(dist) = dist {
+                sensor.data = dist.unwrap();
+            } else {
+                log::info!("Done: {counter}");
+                done = true;
+                break;
+            }
+        }
+
+        if done {
+            break;
+        }
+
+        let enus = sensors.iter().map(|s| {
+            let r = (s.data.dist_h + s.data.dist_v) / 2.0;
+            let h_angle = (180.0 - s.data.angle_h).to_radians();
+            let x = r * h_angle.cos();
+            let y = r * h_angle.sin();
+            Enu {
+                east: Meters::new(x + s.enu.east.as_float()),
+                north: Meters::new(y + s.enu.north.as_float()),
+                up: Meters::new(0.0),
+            }
+        });
+
+        let (count, sum_x, sum_y) = enus.fold((0, 0.0, 0.0), |(count, sum_x, sum_y), enu| {
+            (count + 1, sum_x + enu.east.as_float(), sum_y + enu.north.as_float())
+        });
+        let enu = Enu {
+            east: Meters::new(sum_x / count as f64),
+            north: Meters::new(sum_y / count as f64),
+            up: Meters::new(0.0),
+        };
+
+        let lle = CoordinateSystem::enu_to_lle(&ref_lle, &enu);
+
+        results.push((lle.latitude.as_float(), lle.longitude.as_float(), lle.elevation.as_float()));
+        counter += 1;
+    }
+
+    std::fs::create_dir_all(output_csv.as_ref().parent().unwrap()).unwrap();
+    let mut csv = BufWriter::new(File::create(output_csv).unwrap());
+    writeln!(csv, "lat,lon,alt").unwrap();
+    for r in results {
+        writeln!(csv, "{},{},{}", r.0, r.1, r.2).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: LocationSimArgs,
+    }
+
+    fn reader(text: &str) -> csv::Reader<Box<dyn Read + Send>> {
+        csv::Reader::from_reader(Box::new(Cursor::new(text.as_bytes().to_vec())))
+    }
+
+    /// Directory under the system temporary directory for the files of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("drone_processor_{name}_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_ragged_files_to_the_longest() {
+        let readers = vec![
+            reader("dist\n1\n2\n3\n"),
+            // ends early, with an empty distance before that
+            reader("dist,time\n4,0.1\n,0.2\n"),
+            reader("dist\nabc\n6\n7\n"),
+        ];
+        let paths = ["a", "b", "c"].map(PathBuf::from).to_vec();
+        let rows: Vec<Row> = read_rows(readers, paths)
+            .iter()
+            .map(Result::unwrap)
+            .collect();
+        let dists: Vec<&[f64]> = rows.iter().map(|r| r.dists.as_slice()).collect();
+        // an ended file, an empty cell and an invalid number are all no measurement
+        assert_eq!(dists, [[1.0, 4.0, 0.0], [2.0, 0.0, 6.0], [3.0, 0.0, 7.0]]);
+        let timestamps: Vec<Option<f64>> = rows.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, [Some(0.1), Some(0.2), None]);
+    }
+
+    #[test]
+    fn checks_ragged_and_invalid_inputs() {
+        let dir = temp_dir("check_inputs");
+        let csvs = vec![(1, dir.join("dist_1.csv")), (2, dir.join("dist_2.csv"))];
+        std::fs::write(&csvs[0].1, "dist\nabc\n2\n3\n").unwrap();
+        std::fs::write(&csvs[1].1, "dist\n4\n\n").unwrap();
+        // the frames are those of the longest file, the invalid number only warns
+        assert!(check_inputs(&csvs, 0, Some(3)).is_ok());
+        assert!(matches!(
+            check_inputs(&csvs, 0, Some(4)),
+            Err(SimError::FrameOutOfRange {
+                frame: 4,
+                frames: 3
+            })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replays_a_recording() {
+        use crate::record::Recorder;
+
+        let dir = temp_dir("replay");
+        let recording = dir.join("recording.csv");
+        let positions = [
+            (52.5767, 16.7681),
+            (52.5787, 16.7700),
+            (52.5772, 16.7730),
+            (52.5760, 16.7712),
+        ];
+        // the simulation places the modules around their centroid, and so the target
+        let reference = aggregate::centroid(positions.into_iter()).unwrap();
+        let target = (120.0, -80.0);
+        let mut recorder = Recorder::create(recording.clone(), u64::MAX, None).unwrap();
+        for _ in 0..3 {
+            for (i, &(lat, lon)) in positions.iter().enumerate() {
+                let lle = Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0));
+                let enu = CoordinateSystem::lle_to_enu(&reference, &lle);
+                let dist = (target.0 - enu.east.as_float()).hypot(target.1 - enu.north.as_float());
+                let module = Module {
+                    lat,
+                    lon,
+                    alt: 0.0,
+                    drone: true,
+                    dist,
+                    updated: Instant::now(),
+                    track: None,
+                };
+                recorder.record(&format!("aa:0{i}"), &module).unwrap();
+            }
+        }
+        drop(recorder);
+
+        let cli = Cli::parse_from([
+            "location-sim",
+            "--replay",
+            recording.to_str().unwrap(),
+            "--output-csv",
+            dir.join("out.csv").to_str().unwrap(),
+        ]);
+        let replay = || {
+            let mut estimates = Vec::new();
+            for_each_estimate(&cli.args, |estimate| {
+                estimates.push((estimate.east, estimate.north));
+                Ok(())
+            })
+            .unwrap();
+            estimates
+        };
+        let estimates = replay();
+        let &(east, north) = estimates.last().unwrap();
+        let error = (east - target.0).hypot(north - target.1);
+        assert!(error < 1.0, "({east}, {north}) is {error} m from the target");
+        assert_eq!(replay(), estimates);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_a_module_count_mismatch() {
+        let dir = temp_dir("count_mismatch");
+        let input_dir = dir.join("in");
+        std::fs::create_dir_all(&input_dir).unwrap();
+        for i in 1..=2 {
+            std::fs::write(input_dir.join(format!("dist_{i}.csv")), "dist\n1\n").unwrap();
+        }
+        let modules_csv = dir.join("modules.csv");
+        std::fs::write(&modules_csv, "module,lat,lon\n1,52.5,16.7\n2,52.6,16.7\n3,52.5,16.8\n")
+            .unwrap();
+        let cli = Cli::parse_from([
+            "location-sim",
+            "--input-dir",
+            input_dir.to_str().unwrap(),
+            "--modules-csv",
+            modules_csv.to_str().unwrap(),
+            "--output-csv",
+            dir.join("out.csv").to_str().unwrap(),
+        ]);
+        assert!(matches!(
+            load_inputs(&cli.args),
+            Err(SimError::CountMismatch {
+                modules: 3,
+                files: 2
+            })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
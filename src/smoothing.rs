@@ -0,0 +1,70 @@
+//! Moving average of recent output positions, a display-side alternative to retuning the filter,
+//! and exponential smoothing of the input ranges.
+use circular_buffer::CircularBuffer;
+use geoconv::{Enu, Meters};
+
+/// Largest window accepted by [`MovingAverage::new`].
+pub const MAX_WINDOW: usize = 64;
+
+pub struct MovingAverage {
+    window: usize,
+    points: CircularBuffer<MAX_WINDOW, [f64; 3]>,
+}
+
+impl MovingAverage {
+    /// Average over the last `window` positions, clamped to `1..=MAX_WINDOW`.
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.clamp(1, MAX_WINDOW),
+            points: CircularBuffer::new(),
+        }
+    }
+
+    /// Adds a position and returns the mean of the window.
+    ///
+    /// Averaging is done in ENU meters, as averaging degrees would weight longitude by latitude.
+    pub fn push(&mut self, enu: &Enu) -> Enu {
+        if self.points.len() == self.window {
+            self.points.pop_front();
+        }
+        self.points
+            .push_back([enu.east.as_float(), enu.north.as_float(), enu.up.as_float()]);
+        let n = self.points.len() as f64;
+        let sum = self
+            .points
+            .iter()
+            .fold([0.0; 3], |acc, p| [acc[0] + p[0], acc[1] + p[1], acc[2] + p[2]]);
+        Enu {
+            east: Meters::new(sum[0] / n),
+            north: Meters::new(sum[1] / n),
+            up: Meters::new(sum[2] / n),
+        }
+    }
+}
+
+/// Exponential moving average after adding `value` with weight `alpha`, starting from `value`
+/// when there is no `previous` average.
+pub fn ema(previous: Option<f64>, value: f64, alpha: f64) -> f64 {
+    match previous {
+        Some(previous) => previous + alpha * (value - previous),
+        None => value,
+    }
+}
+
+/// Parses a `--range-smoothing` argument, the weight of a new range between 0 and 1.
+pub fn parse_alpha(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        Ok(_) => Err("must be greater than 0 and at most 1".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parses a `--smooth-window` argument.
+pub fn parse_window(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(window) if (1..=MAX_WINDOW).contains(&window) => Ok(window),
+        Ok(_) => Err(format!("must be between 1 and {MAX_WINDOW}")),
+        Err(err) => Err(err.to_string()),
+    }
+}
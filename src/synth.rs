@@ -0,0 +1,106 @@
+//! Synthetic range measurements for exercising the filter without recordings.
+use geoconv::Enu;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::ekf::{Real, Sensor};
+
+/// Seeded source of Gaussian noise, giving the same sequence for the same seed.
+pub struct Noise {
+    rng: StdRng,
+    /// Second sample of the last Box-Muller draw.
+    spare: Option<f64>,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            spare: None,
+        }
+    }
+
+    /// Sample of a zero-mean normal distribution with standard deviation `stddev`.
+    pub fn gaussian(&mut self, stddev: f64) -> f64 {
+        if let Some(spare) = self.spare.take() {
+            return spare * stddev;
+        }
+        // 1 - u is in (0, 1], keeping the logarithm finite
+        let radius = (-2.0 * (1.0 - self.rng.random::<f64>()).ln()).sqrt();
+        let angle = std::f64::consts::TAU * self.rng.random::<f64>();
+        self.spare = Some(radius * angle.sin());
+        radius * angle.cos() * stddev
+    }
+}
+
+/// Sensors reporting the range to each position of `trajectory`, one frame per position, with
+/// Gaussian noise of standard deviation `stddev` in meters.
+///
+/// Positions are in the ENU frame the sensors are placed in. Noisy ranges are kept positive so
+/// that the filter does not take them for missing.
+pub fn range_frames(
+    trajectory: &[Enu],
+    sensors: &[Sensor],
+    stddev: Real,
+    noise: &mut Noise,
+) -> Vec<Vec<Sensor>> {
+    trajectory
+        .iter()
+        .map(|target| {
+            let t = [
+                target.east.as_float() as Real,
+                target.north.as_float() as Real,
+                target.up.as_float() as Real,
+            ];
+            sensors
+                .iter()
+                .map(|sensor| {
+                    let s = sensor.position();
+                    let dist = (0..3).map(|i| (t[i] - s[i]).powi(2)).sum::<Real>().sqrt();
+                    let error = noise.gaussian(stddev as f64) as Real;
+                    Sensor {
+                        dist: (dist + error).max(Real::MIN_POSITIVE),
+                        ..*sensor
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Range differences to each position of `trajectory`, one frame per position: the noisy range
+/// of every sensor minus that of the sensor at index `reference`, as with
+/// [`RangeModel::Tdoa`](crate::ekf::RangeModel::Tdoa).
+///
+/// The reference keeps its own range, which the filter ignores.
+pub fn tdoa_frames(
+    trajectory: &[Enu],
+    sensors: &[Sensor],
+    reference: usize,
+    stddev: Real,
+    noise: &mut Noise,
+) -> Vec<Vec<Sensor>> {
+    let mut frames = range_frames(trajectory, sensors, stddev, noise);
+    for frame in &mut frames {
+        let reference_dist = frame[reference].dist;
+        for (i, sensor) in frame.iter_mut().enumerate() {
+            if i != reference {
+                sensor.dist -= reference_dist;
+            }
+        }
+    }
+    frames
+}
+
+/// Root mean square of the horizontal distances between `estimates` and `truth`, position by
+/// position, in meters.
+pub fn rms_error(estimates: &[(Real, Real)], truth: &[Enu]) -> f64 {
+    let n = estimates.len().min(truth.len()).max(1);
+    let sum: f64 = estimates
+        .iter()
+        .zip(truth)
+        .map(|(&(x, y), t)| {
+            (x as f64 - t.east.as_float()).powi(2) + (y as f64 - t.north.as_float()).powi(2)
+        })
+        .sum();
+    (sum / n as f64).sqrt()
+}
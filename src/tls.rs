@@ -0,0 +1,55 @@
+//! TLS for the processor's inbound WebSocket server.
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    ServerConfig, ServerConnection, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+
+/// A connection accepted by the server, either plain or wrapped in TLS.
+pub trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+/// Builds a server configuration from a PEM certificate chain and a PEM private key.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("reading {}: {err}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|err| format!("reading {}: {err}", key.display()))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+    Ok(Arc::new(config))
+}
+
+/// Wraps `stream` in a server-side TLS session if `config` is set, the handshake happening on
+/// the first read or write.
+pub fn wrap(
+    stream: TcpStream,
+    config: Option<&Arc<ServerConfig>>,
+) -> std::io::Result<Box<dyn Stream>> {
+    Ok(match config {
+        Some(config) => {
+            let conn = ServerConnection::new(config.clone()).map_err(std::io::Error::other)?;
+            Box::new(StreamOwned::new(conn, stream))
+        }
+        None => Box::new(stream),
+    })
+}
+
+/// URL of the outbound WebSocket, `ws://` unless `addr` already has a scheme such as `wss://`.
+pub fn client_url(addr: &str) -> String {
+    if addr.contains("://") {
+        addr.to_owned()
+    } else {
+        format!("ws://{addr}")
+    }
+}
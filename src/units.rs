@@ -0,0 +1,24 @@
+//! Length units of the output, everything is computed in meters.
+
+/// Meters in an international foot.
+pub const METERS_PER_FOOT: f64 = 0.3048;
+
+/// Unit of the altitudes, distances and velocities written out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Units {
+    /// Meters, and meters per second for velocities
+    #[default]
+    Meters,
+    /// International feet, and feet per second for velocities
+    Feet,
+}
+
+impl Units {
+    /// Converts a length in meters, or a velocity in m/s, to this unit.
+    pub fn from_meters(self, value: f64) -> f64 {
+        match self {
+            Units::Meters => value,
+            Units::Feet => value / METERS_PER_FOOT,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Conversion of WGS84 latitude and longitude into UTM coordinates.
+
+const SEMI_MAJOR_AXIS: f64 = 6_378_137.0;
+const FLATTENING: f64 = 1.0 / 298.257_223_563;
+const SCALE_FACTOR: f64 = 0.9996;
+const FALSE_EASTING: f64 = 500_000.0;
+const FALSE_NORTHING_SOUTH: f64 = 10_000_000.0;
+
+/// Position in a UTM zone, in meters.
+#[derive(Clone, Copy, Debug)]
+pub struct Utm {
+    pub easting: f64,
+    pub northing: f64,
+    pub zone: u8,
+    /// Whether the northing is measured in the northern hemisphere convention.
+    pub north: bool,
+}
+
+/// UTM zone (1 to 60) containing `lon`, ignoring the Norway and Svalbard exceptions.
+pub fn zone(lon: f64) -> u8 {
+    (((lon + 180.0) / 6.0).floor() as i64).rem_euclid(60) as u8 + 1
+}
+
+/// Projects `lat`/`lon` in degrees into the given `zone`, which may be a neighbouring one to
+/// keep a track that crosses a zone boundary continuous.
+///
+/// Uses the series expansion from Snyder, "Map Projections: A Working Manual" (1987), accurate
+/// to well below a meter within a few degrees of the central meridian.
+pub fn from_lat_lon(lat: f64, lon: f64, zone: u8) -> Utm {
+    let e2 = FLATTENING * (2.0 - FLATTENING);
+    let (e4, e6) = (e2 * e2, e2 * e2 * e2);
+    let ep2 = e2 / (1.0 - e2);
+
+    let phi = lat.to_radians();
+    let central_meridian = f64::from(zone) * 6.0 - 183.0;
+    let (sin, cos, tan) = (phi.sin(), phi.cos(), phi.tan());
+
+    let n = SEMI_MAJOR_AXIS / (1.0 - e2 * sin * sin).sqrt();
+    let t = tan * tan;
+    let c = ep2 * cos * cos;
+    let a = cos * (lon - central_meridian).to_radians();
+    // meridional arc from the equator
+    let m = SEMI_MAJOR_AXIS
+        * ((1.0 - e2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0) * phi
+            - (3.0 * e2 / 8.0 + 3.0 * e4 / 32.0 + 45.0 * e6 / 1024.0) * (2.0 * phi).sin()
+            + (15.0 * e4 / 256.0 + 45.0 * e6 / 1024.0) * (4.0 * phi).sin()
+            - (35.0 * e6 / 3072.0) * (6.0 * phi).sin());
+
+    let easting = SCALE_FACTOR
+        * n
+        * (a + (1.0 - t + c) * a.powi(3) / 6.0
+            + (5.0 - 18.0 * t + t * t + 72.0 * c - 58.0 * ep2) * a.powi(5) / 120.0)
+        + FALSE_EASTING;
+    let mut northing = SCALE_FACTOR
+        * (m + n
+            * tan
+            * (a * a / 2.0
+                + (5.0 - t + 9.0 * c + 4.0 * c * c) * a.powi(4) / 24.0
+                + (61.0 - 58.0 * t + t * t + 600.0 * c - 330.0 * ep2) * a.powi(6) / 720.0));
+    let north = lat >= 0.0;
+    if !north {
+        northing += FALSE_NORTHING_SOUTH;
+    }
+
+    Utm {
+        easting,
+        northing,
+        zone,
+        north,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that `lat`/`lon` projects into `zone` within 5 mm of `easting`/`northing`.
+    ///
+    /// The expected values were computed independently with the 6th-order Krüger series of
+    /// Karney, "Transverse Mercator with an accuracy of a few nanometers" (2011), the method of
+    /// GeographicLib.
+    fn assert_projects(lat: f64, lon: f64, zone: u8, easting: f64, northing: f64) {
+        let utm = from_lat_lon(lat, lon, zone);
+        let error = (utm.easting - easting).hypot(utm.northing - northing);
+        assert!(error < 0.005, "{utm:?} is {error} m from ({easting}, {northing})");
+    }
+
+    #[test]
+    fn projects_reference_points() {
+        assert_eq!(zone(16.7), 33);
+        assert_projects(52.5, 16.7, 33, 615_396.849, 5_818_010.359);
+        assert_eq!(zone(18.4), 34);
+        assert_projects(-33.9, 18.4, 34, 259_583.222, 6_245_888.045);
+        assert!(!from_lat_lon(-33.9, 18.4, 34).north);
+    }
+
+    #[test]
+    fn projects_at_a_zone_edge() {
+        assert_eq!(zone(17.999), 33);
+        assert_eq!(zone(18.0), 34);
+        assert_projects(52.5, 17.999, 33, 703_557.311, 5_820_880.158);
+        // the boundary is 3 degrees from both central meridians
+        assert_projects(52.5, 18.0, 33, 703_625.170, 5_820_882.979);
+        assert_projects(52.5, 18.0, 34, 296_374.830, 5_820_882.979);
+    }
+}
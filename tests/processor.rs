@@ -0,0 +1,111 @@
+//! Drives the real-time processor end to end: modules report over its inbound WebSocket and the
+//! estimates are read from a sink standing in for the website.
+use std::{
+    net::{TcpListener, TcpStream},
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use drone_processor::processor::{Processor, ProcessorConfig};
+use geoconv::{CoordinateSystem, Degrees, Lle, Meters, Wgs84};
+use tungstenite::{Message, WebSocket, accept, connect};
+
+const DRONE: (f64, f64) = (52.5775, 16.7705);
+const MODULES: [(&str, f64, f64); 4] = [
+    ("aa:00:00:00:00:01", 52.5767, 16.7681),
+    ("aa:00:00:00:00:02", 52.5787, 16.7700),
+    ("aa:00:00:00:00:03", 52.5750, 16.7710),
+    ("aa:00:00:00:00:04", 52.5772, 16.7730),
+];
+/// How close the estimate has to get to the drone, in meters.
+const TOLERANCE: f64 = 1.0;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    config: ProcessorConfig,
+}
+
+fn lle(lat: f64, lon: f64) -> Lle<Wgs84> {
+    Lle::<Wgs84>::new(Degrees::new(lat), Degrees::new(lon), Meters::new(0.0))
+}
+
+/// Horizontal distance in meters between two points.
+fn distance(from: &Lle<Wgs84>, to: &Lle<Wgs84>) -> f64 {
+    let enu = CoordinateSystem::lle_to_enu(from, to);
+    enu.east.as_float().hypot(enu.north.as_float())
+}
+
+/// Starts a processor with the extra `args` and modules reporting the drone to it, returning
+/// the website end of its outbound WebSocket.
+fn start(args: &[&str]) -> WebSocket<TcpStream> {
+    let sink = TcpListener::bind("127.0.0.1:0").unwrap();
+    let ws_out = sink.local_addr().unwrap().to_string();
+    let cli = Cli::parse_from(
+        ["processor", "--ws-in", "127.0.0.1:0", "--ws-out", &ws_out]
+            .iter()
+            .chain(args),
+    );
+    let processor = Processor::bind(cli.config).unwrap();
+    let ws_in = processor.local_addr().unwrap();
+    spawn(move || processor.run());
+
+    let drone = lle(DRONE.0, DRONE.1);
+    let reports: Vec<String> = MODULES
+        .iter()
+        .map(|&(mac, lat, lon)| {
+            let dist = distance(&lle(lat, lon), &drone);
+            format!("{mac}|10.0.0.1|{lat}|{lon}|true|{dist}")
+        })
+        .collect();
+    // reports are only retained briefly, so keep sending them like the modules do
+    spawn(move || {
+        let (mut socket, _response) = connect(format!("ws://{ws_in}")).unwrap();
+        loop {
+            for report in &reports {
+                socket.send(Message::Text(report.as_str().into())).unwrap();
+            }
+            sleep(Duration::from_millis(50));
+        }
+    });
+
+    let (stream, _addr) = sink.accept().unwrap();
+    accept(stream).unwrap()
+}
+
+#[test]
+fn converges_on_a_stationary_drone() {
+    let mut website = start(&[]);
+    let drone = lle(DRONE.0, DRONE.1);
+    let start = Instant::now();
+    let mut error = f64::INFINITY;
+    while start.elapsed() < TIMEOUT {
+        let message = website.read().unwrap();
+        let Ok(text) = message.to_text() else {
+            continue;
+        };
+        let fields: Vec<f64> = text.split(',').map(|f| f.parse().unwrap()).collect();
+        let (lon, lat) = (fields[0], fields[1]);
+        error = distance(&drone, &lle(lat, lon));
+        if error < TOLERANCE {
+            return;
+        }
+    }
+    panic!("the estimate is still {error:.2} m from the drone after {TIMEOUT:?}");
+}
+
+#[test]
+fn limits_the_outbound_rate() {
+    let mut website = start(&["--limit-rate", "5"]);
+    website.read().unwrap();
+    let start = Instant::now();
+    let mut messages = 0;
+    while start.elapsed() < Duration::from_secs(2) {
+        website.read().unwrap();
+        messages += 1;
+    }
+    // 10 at 5 Hz, against 40 at the 50 ms frame rate without the limit
+    assert!((8..=12).contains(&messages), "{messages} messages in 2 s");
+}
@@ -0,0 +1,139 @@
+//! Filter accuracy on synthetic ranges with a known ground truth.
+#![allow(non_snake_case)]
+#![cfg_attr(not(feature = "f32"), allow(clippy::unnecessary_cast))]
+
+use drone_processor::{
+    Ekf, Sensor,
+    ekf::{EkfConfig, RangeModel, Real},
+    synth::{self, Noise},
+};
+use geoconv::{Enu, Meters};
+
+const DT: Real = 0.05;
+const STEPS: usize = 400;
+const RANGE_STDDEV: Real = 3.0;
+
+fn enu(east: f64, north: f64) -> Enu {
+    Enu {
+        east: Meters::new(east),
+        north: Meters::new(north),
+        up: Meters::new(0.0),
+    }
+}
+
+fn sensors() -> Vec<Sensor> {
+    [(-150.0, -40.0), (0.0, 180.0), (70.0, -220.0), (200.0, 30.0)]
+        .into_iter()
+        .map(|(east, north)| Sensor {
+            enu: enu(east, north),
+            dist: 0.0,
+            stddev: Some(RANGE_STDDEV),
+            max_dist: None,
+            bearing: None,
+        })
+        .collect()
+}
+
+/// Straight flight at 10 m/s towards the north-east.
+fn trajectory() -> Vec<Enu> {
+    (0..STEPS)
+        .map(|i| {
+            let t = i as f64 * DT as f64;
+            enu(-50.0 + 7.0 * t, -50.0 + 7.0 * t)
+        })
+        .collect()
+}
+
+#[test]
+fn tracks_a_straight_flight() {
+    let truth = trajectory();
+    let frames = synth::range_frames(&truth, &sensors(), RANGE_STDDEV, &mut Noise::new(7));
+    let mut ekf = Ekf::with_config(0.0, 0.0, EkfConfig::default());
+    let estimates: Vec<(Real, Real)> = frames
+        .iter()
+        .map(|sensors| {
+            let (x_pred, P_pred) = ekf.predict(DT);
+            ekf.update(x_pred, P_pred, sensors);
+            (ekf.x_est[0], ekf.x_est[1])
+        })
+        .collect();
+    // leave out the first seconds while the filter converges
+    let error = synth::rms_error(&estimates[40..], &truth[40..]);
+    assert!(error < 1.5, "RMS error {error:.2} m");
+}
+
+#[test]
+fn wls_fixes_a_straight_flight() {
+    let truth = trajectory();
+    let frames = synth::range_frames(&truth, &sensors(), RANGE_STDDEV, &mut Noise::new(7));
+    let mut ekf = Ekf::with_config(0.0, 0.0, EkfConfig::default());
+    let estimates: Vec<(Real, Real)> = frames
+        .iter()
+        .map(|sensors| {
+            ekf.solve_wls(sensors);
+            (ekf.x_est[0], ekf.x_est[1])
+        })
+        .collect();
+    // every frame on its own, noisier than the filter but with nothing to converge
+    let error = synth::rms_error(&estimates, &truth);
+    assert!(error < RANGE_STDDEV as f64 * 2.0, "RMS error {error:.2} m");
+    assert_eq!(ekf.velocity(), (0.0, 0.0));
+}
+
+#[test]
+fn tracks_a_straight_flight_from_range_differences() {
+    let truth = trajectory();
+    let frames = synth::tdoa_frames(&truth, &sensors(), 0, RANGE_STDDEV, &mut Noise::new(7));
+    let config = EkfConfig {
+        range_model: RangeModel::Tdoa,
+        tdoa_reference: 0,
+        ..Default::default()
+    };
+    let mut ekf = Ekf::with_config(0.0, 0.0, config);
+    let estimates: Vec<(Real, Real)> = frames
+        .iter()
+        .map(|sensors| {
+            let (x_pred, P_pred) = ekf.predict(DT);
+            ekf.update(x_pred, P_pred, sensors);
+            (ekf.x_est[0], ekf.x_est[1])
+        })
+        .collect();
+    // one range fewer, and the error of the reference range in every difference
+    let error = synth::rms_error(&estimates[40..], &truth[40..]);
+    assert!(error < 3.0, "RMS error {error:.2} m");
+}
+
+#[test]
+fn covariance_is_consistent_on_a_straight_flight() {
+    let truth = trajectory();
+    let frames = synth::range_frames(&truth, &sensors(), RANGE_STDDEV, &mut Noise::new(7));
+    let mut ekf = Ekf::with_config(0.0, 0.0, EkfConfig::default());
+    let nees: Vec<Real> = frames
+        .iter()
+        .zip(&truth)
+        .map(|(sensors, t)| {
+            let (x_pred, P_pred) = ekf.predict(DT);
+            ekf.update(x_pred, P_pred, sensors);
+            ekf.position_nees(t.east.as_float() as Real, t.north.as_float() as Real)
+                .unwrap()
+        })
+        .skip(40)
+        .collect();
+    let mean = nees.iter().sum::<Real>() / nees.len() as Real;
+    // 2 for a perfectly consistent filter, the process noise leaves it somewhat conservative
+    assert!((0.5..4.0).contains(&mean), "mean NEES {mean:.2}");
+}
+
+#[test]
+fn same_seed_gives_same_ranges() {
+    let truth = trajectory();
+    let dists = |seed| -> Vec<Real> {
+        synth::range_frames(&truth, &sensors(), RANGE_STDDEV, &mut Noise::new(seed))
+            .iter()
+            .flatten()
+            .map(|s| s.dist)
+            .collect()
+    };
+    assert_eq!(dists(1), dists(1));
+    assert_ne!(dists(1), dists(2));
+}